@@ -0,0 +1,5 @@
+#!/bin/bash
+set -e -u -o pipefail
+
+# Helm calls downloaders with: certFile keyFile caFile URL
+exec culper ${CULPER_HOME:+--home "$CULPER_HOME"} helm downloader "$@"
@@ -0,0 +1,5 @@
+#!/bin/bash
+set -e -u -o pipefail
+
+# Helm pipes the rendered manifests through stdin and reads them from stdout.
+exec culper ${CULPER_HOME:+--home "$CULPER_HOME"} helm post-render
@@ -132,10 +132,10 @@ impl DecryptionHelper for Helper {
 
                         if key.secret().map(|s| s.is_encrypted()).unwrap_or(false) {
                             loop {
-                                let p = rpassword::prompt_password_stderr(&format!(
+                                let p = rpassword::read_password_from_tty(Some(&format!(
                                     "Enter password to decrypt key {}: ",
                                     self.key_hints.get(keyid).unwrap()
-                                ))?
+                                )))?
                                 .into();
 
                                 if let Ok(mpis) = key.secret().unwrap().decrypt(key.pk_algo(), &p) {
@@ -160,9 +160,9 @@ impl DecryptionHelper for Helper {
                         return Err(failure::err_msg("No key to decrypt message"));
                     }
                     return Ok(Some(Secret::Symmetric {
-                        password: rpassword::prompt_password_stderr(
+                        password: rpassword::read_password_from_tty(Some(
                             "Enter password to decrypt message: ",
-                        )?
+                        ))?
                         .into(),
                     }));
                 }
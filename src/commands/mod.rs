@@ -12,7 +12,7 @@ extern crate sequoia;
 use sequoia::core::Context;
 use sequoia::openpgp::armor;
 use sequoia::openpgp::constants::DataFormat;
-use sequoia::openpgp::packet::Signature;
+use sequoia::openpgp::packet::{Key, Signature};
 use sequoia::openpgp::parse::stream::{
     DetachedVerifier, VerificationHelper, VerificationResult, Verifier,
 };
@@ -45,6 +45,12 @@ pub fn encrypt(
     let recipients: Vec<&sequoia::openpgp::TPK> = recipients_tpks.iter().collect();
     let signers: Vec<&sequoia::openpgp::TPK> = signers_tpks.iter().collect();
 
+    // The Signer picks the signing key of each TPK itself. It may be a
+    // subkey, which has to be unlocked either way.
+    for tpk in &signers {
+        signing_key(tpk)?;
+    }
+
     // Build a vector of references to hand to Encryptor.
     let mut crypted_data: Vec<u8> = vec![];
 
@@ -70,6 +76,54 @@ pub fn encrypt(
     Ok(crypted_data)
 }
 
+/// Returns the unlocked signing-capable key of a TPK, looking at the
+/// primary key first and then at the subkeys.
+fn signing_key(tpk: &TPK) -> Result<&Key> {
+    let can_sign = |key: &Key, sig: Option<&Signature>| -> bool {
+        key.secret().is_some() && sig.map(|sig| sig.key_flags().can_sign()).unwrap_or(false)
+    };
+
+    let key = if can_sign(tpk.primary(), tpk.primary_key_signature()) {
+        Some(tpk.primary())
+    } else {
+        tpk.subkeys()
+            .map(|skb| (skb.subkey(), skb.binding_signature()))
+            .find(|(key, sig)| can_sign(key, *sig))
+            .map(|(key, _)| key)
+    };
+    let key = key.ok_or_else(|| {
+        failure::err_msg(format!("{} has no key to sign with", tpk.fingerprint()))
+    })?;
+
+    match key.secret() {
+        Some(secret) if !secret.is_encrypted() => Ok(key),
+        _ => Err(failure::err_msg(format!(
+            "Signing key {} is locked",
+            key.fingerprint()
+        ))),
+    }
+}
+
+/// Returns the ids of the keys a message is encrypted for.
+pub fn recipients(message: &[u8]) -> Result<Vec<KeyID>> {
+    let mut recipients = vec![];
+    let mut ppr = sequoia::openpgp::parse::PacketParser::from_bytes(message)?;
+
+    while let PacketParserResult::Some(pp) = ppr {
+        let (packet, ppr_tmp) = pp.next()?;
+        ppr = ppr_tmp;
+
+        match packet {
+            Packet::PKESK(pkesk) => recipients.push(pkesk.recipient().clone()),
+            Packet::SKESK(_) => (),
+            // The session keys precede the encrypted data.
+            _ => break,
+        }
+    }
+
+    Ok(recipients)
+}
+
 pub fn sign(
     input: &mut io::Read,
     output_path: Option<&str>,
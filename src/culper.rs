@@ -16,37 +16,63 @@ extern crate serde_derive;
 extern crate base64;
 extern crate culper_lib;
 extern crate sequoia;
-extern crate serde_yaml;
+extern crate serde_json;
+extern crate hmac;
+extern crate rand;
+extern crate sha2;
 extern crate toml;
+extern crate toml_edit;
+extern crate yaml_rust;
 #[macro_use]
 extern crate self_update;
 
 use failure::ResultExt;
 use prettytable::{Cell, Row, Table};
 use promptly::prompt;
-use std::fs::{File, OpenOptions};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
 use std::path::Path;
-use std::process::exit;
+use std::process::{exit, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use culper_lib::config;
 use culper_lib::config::{CulperConfig, UserConfig};
 use culper_lib::vault;
 use culper_lib::vault::{OpenableVault, SealableVault, SealedVault};
+use format::{Format, Sealed};
 
-use base64::encode;
+use base64::{decode, encode};
 use clap::ArgMatches;
 use failure::Error;
 use sequoia::core::Context;
 use sequoia::openpgp::armor::{Kind, Writer};
 use sequoia::openpgp::parse::Parse;
 use sequoia::openpgp::serialize::Serialize;
-use sequoia::openpgp::TPK;
+use sequoia::openpgp::constants::PublicKeyAlgorithm;
+use sequoia::openpgp::packet::key::SecretKey;
+use sequoia::openpgp::tpk::{CipherSuite, TPKBuilder};
+use sequoia::openpgp::{KeyID, Packet, PacketPile, TPK};
 use sequoia::store::{LogIter, Store};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use std::path::PathBuf;
+use tempfile::NamedTempFile;
 use url::Url;
 
+/// Allowlist which `lint` uses if none is given.
+const DEFAULT_ALLOWLIST: &str = ".culper-allowlist";
+
+/// Scheme of the URIs handled by the Helm downloader plugin.
+const HELM_SCHEME: &str = "culper://";
+
+/// Git config key of the secret which keys the hashes of redacted values.
+const REDACT_KEY: &str = "culper.redactkey";
+
 #[derive(Serialize, Deserialize)]
 struct RegisterAdminRequest {
     name: String,
@@ -172,17 +198,21 @@ fn help_warning(arg: &str) {
 }
 
 fn real_main() -> Result<(), failure::Error> {
-    if matches.subcommand().0 != "setup" {
+    // Linting runs in CI, where there is no key to decrypt anything.
+    if !["setup", "lint"].contains(&matches.subcommand().0) {
       match config_reader.clone().read() {
         Ok(_) => Ok(()),
         Err(_) => Err(format_err!("Could not read config file. Create a config file by running \n\n \
-                                    gpg -a --export-secret-keys <your-key-id> | culper setup --name=<your-name-or-alias>"))
+                                    gpg -a --export-secret-keys <your-key-id> | culper setup --name=<your-name-or-alias>\n\n \
+                                    or generate a new key with\n\n \
+                                    culper setup --generate --name=<your-name-or-alias>"))
       }?;
     }
 
     let ctx = Context::configure("localhost")
         .home(home_dir(matches.value_of("home")))
         .build()?;
+    let keyring = Keyring::new(&ctx);
 
     match matches.subcommand() {
         ("self-update", Some(m)) => {
@@ -238,23 +268,44 @@ fn real_main() -> Result<(), failure::Error> {
                 matches.value_of("priv_key"),
             );
 
-            // read key from stdin
-            let mut in_key_bytes = vec![];
-            open_or_stdin(None)?.read_to_end(&mut in_key_bytes)?;
+            let (tpk, in_key_bytes) = if m.is_present("generate") {
+                let tpk = generate_key(name, m.is_present("passphrase"))?;
+                eprintln!("Generated key {}", tpk.fingerprint());
+                (tpk.clone(), armored_secret_key(tpk)?)
+            } else {
+                // read key from stdin
+                let mut in_key_bytes = vec![];
+                open_or_stdin(None)?.read_to_end(&mut in_key_bytes)?;
+
+                // Validate given key.
+                let tpk = TPK::from_bytes(&in_key_bytes).context("Given key is not parsable.")?;
 
-            // Validate given key.
-            let tpk = TPK::from_bytes(&in_key_bytes).context("Given key is not parsable.")?;
+                // Offer to protect keys which come without a passphrase.
+                let mut unprotected = false;
+                map_secret_keys(tpk.clone(), |_, secret| {
+                    unprotected |= !secret.is_encrypted();
+                    Ok(())
+                })?;
+                let passphrase = if !unprotected || m.is_present("no_passphrase") {
+                    None
+                } else if !has_terminal() {
+                    eprintln!("No terminal to ask for a passphrase, storing the key unprotected.");
+                    None
+                } else {
+                    eprintln!("The private key is not protected by a passphrase.");
+                    new_passphrase()?
+                };
+                match passphrase {
+                    Some(passphrase) => {
+                        let tpk = lock_secret_keys(tpk, passphrase)?;
+                        (tpk.clone(), armored_secret_key(tpk)?)
+                    }
+                    None => (tpk, in_key_bytes),
+                }
+            };
 
             // write out distinct key to config path.
-            let mut output_key = create_or_stdout(
-                Some(
-                    priv_key_path_str
-                        .to_str()
-                        .expect("Failed creating path string for private key."),
-                ),
-                true,
-            )?;
-            output_key.write_all(&in_key_bytes)?;
+            write_priv_key(&priv_key_path_str, &in_key_bytes)?;
 
             // write to config
             let fingerprint = tpk.fingerprint();
@@ -281,103 +332,517 @@ fn real_main() -> Result<(), failure::Error> {
             };
             config_reader.clone().update(new_config).write()?;
         }
-        ("encrypt", Some(m)) => {
-            let mut recipients: Vec<sequoia::openpgp::TPK> = vec![];
-            let target_store = Store::open(&ctx, "targets").context("Failed to open the store")?;
-            let admin_store = Store::open(&ctx, "admins").context("Failed to open the store")?;
+        ("key", Some(m)) => match m.subcommand() {
+            ("passwd", Some(_)) => {
+                let priv_tpk = unlock_priv_key()?;
+                let priv_tpk = match new_passphrase()? {
+                    Some(passphrase) => lock_secret_keys(priv_tpk, passphrase)?,
+                    None => priv_tpk,
+                };
 
-            let targets: Result<Vec<_>, _> = target_store
-                .iter()?
-                .map(|(_, _, binding)| binding.tpk())
-                .collect();
-            let admins: Result<Vec<_>, _> = admin_store
-                .iter()?
-                .map(|(_, _, binding)| binding.tpk())
-                .collect();
+                write_priv_key(
+                    &priv_key_path(home_dir(matches.value_of("home")), matches.value_of("priv_key")),
+                    &armored_secret_key(priv_tpk)?,
+                )?;
+            }
+            _ => unreachable!("clap requires a subcommand"),
+        },
+        ("encrypt", Some(_)) => {
+            eprintln!("Enter value to decrypt");
+            let value: String = prompt("");
+            let sealed_vault = seal_value(&keyring, value)?;
+
+            println!("{}", sealed_vault.to_string());
+        }
+        ("set", Some(m)) => {
+            let ifile = m.value_of("file").unwrap(); // clap handles this;
+            let format = Format::detect(ifile, m.value_of("format"))?;
+            let path = yaml::parse_path(m.value_of("path").unwrap())?;
+            let contents = if Path::new(ifile).exists() {
+                load_yml(ifile.to_string())?
+            } else {
+                String::new()
+            };
 
-            recipients.extend(targets?);
-            recipients.extend(admins?);
+            // Reports a broken file or path before the secret is typed in.
+            format.set(contents.clone(), &path, "")?;
 
-            let mut priv_tpk = TPK::from_bytes(priv_key.as_bytes())?;
+            eprintln!("Enter value to encrypt");
+            let value: String = prompt("");
+            let sealed_vault = seal_value(&keyring, value)?;
+
+            write_in_place(ifile, &format.set(contents, &path, &sealed_vault.to_string())?)?;
+        }
+        ("decrypt", Some(m)) => {
+            let ifile = m.value_of("file").unwrap(); // clap handles this;
+            let format = Format::detect(ifile, m.value_of("format"))?;
+            let yml = load_yml(ifile.to_string())?;
+            let jobs = m.value_of("jobs").unwrap().parse()?; // clap validates this
 
-            let pair = priv_tpk.primary_mut();
-            match pair.secret_mut() {
-                Some(secret) => {
-                    if secret.is_encrypted() {
-                        let password = rpassword::prompt_password_stderr(
-                            "Enter password to decrypt private key: ",
-                        )
-                        .context("Could not read password from stdin.")?;
+            let plain_secrets = unseal_file(&keyring, format, &yml, jobs)?;
+            let replacefn = |val: &mut String| Ok(plain_secrets.get(val.as_str()).cloned());
 
-                        secret.decrypt_in_place(
-                            sequoia::openpgp::constants::PublicKeyAlgorithm::RSAEncryptSign,
-                            &password.into(),
-                        )
-                    } else {
-                        Ok(())
-                    }
+            let uncrypted_yml = format.traverse(&yml, &replacefn)?;
+            match m.value_of("output_format") {
+                Some(style) => {
+                    let variables = format.variables(
+                        uncrypted_yml,
+                        m.value_of("separator").unwrap_or("_"),
+                        !m.is_present("preserve_case"),
+                    )?;
+                    let style = dotenv::Style::from_name(style)?;
+                    print!("{}", dotenv::format_variables(&variables, style)?)
                 }
-                None => Err(format_err!("Could not access secret key")),
-            }?;
+                None => print!("{}", uncrypted_yml),
+            }
+        }
+        ("exec", Some(m)) => {
+            let ifile = m.value_of("file").unwrap(); // clap handles this;
+            let format = Format::detect(ifile, m.value_of("format"))?;
+            let yml = load_yml(ifile.to_string())?;
+            let jobs = m.value_of("jobs").unwrap().parse()?; // clap validates this
 
-            let priv_tpk: Vec<sequoia::openpgp::TPK> = vec![priv_tpk];
-            recipients.extend(priv_tpk.clone());
-            eprintln!("Enter value to decrypt");
-            let value: String = prompt("");
-            let vault =
-                vault::UnsealedVault::new(value.to_owned(), vault::EncryptionFormat::GPG_KEY);
-            let sealed_vault = vault.seal(&move |vault: vault::UnsealedVault| {
-                let secret_bytes = vault.plain_secret.as_bytes();
-                let data =
-                    commands::encrypt(secret_bytes.to_vec(), recipients.clone(), priv_tpk.clone())?;
-
-                Ok(vault::SealedVault::new(data, vault.format))
-            })?;
+            let plain_secrets = unseal_file(&keyring, format, &yml, jobs)?;
+            let replacefn = |val: &mut String| Ok(plain_secrets.get(val.as_str()).cloned());
 
-            println!("{}", sealed_vault.to_string());
+            let variables = format.variables(
+                format.traverse(&yml, &replacefn)?,
+                m.value_of("separator").unwrap_or("_"),
+                !m.is_present("preserve_case"),
+            )?;
+
+            let mut command = m.values_of("command").unwrap(); // clap handles this;
+            let program = command.next().unwrap();
+            let mut child = Command::new(program);
+            child.args(command).envs(variables);
+            exec_command(child, program)?;
         }
-        ("decrypt", Some(m)) => {
+        ("k8s-secret", Some(m)) => {
             let ifile = m.value_of("file").unwrap(); // clap handles this;
-            let mut yml = load_yml(ifile.to_string())?;
-
-            let replacefn = |val: &mut String| match vault::parse(val) {
-                Ok(vault) => {
-                    let unsealed_vault = vault.unseal(&|sealed_vault: SealedVault| {
-                        let priv_tpk = TPK::from_bytes(priv_key.as_bytes())?;
-
-                        let mut recipients: Vec<sequoia::openpgp::TPK> = vec![];
-                        let owner_store =
-                            Store::open(&ctx, "owner").context("Failed to open the store")?;
-                        let admin_store =
-                            Store::open(&ctx, "admins").context("Failed to open the store")?;
-
-                        let owner: Result<Vec<_>, _> = owner_store
-                            .iter()?
-                            .map(|(_, _, binding)| binding.tpk())
-                            .collect();
-                        let admins: Result<Vec<_>, _> = admin_store
-                            .iter()?
-                            .map(|(_, _, binding)| binding.tpk())
-                            .collect();
-
-                        recipients.extend(owner?);
-                        recipients.extend(admins?);
-                        recipients.extend(vec![priv_tpk.clone()]);
-
-                        let data =
-                            commands::decrypt(sealed_vault.secret, 1, recipients, vec![priv_tpk])?;
-                        Ok(vault::UnsealedVault::new(
-                            String::from_utf8(data)?,
-                            sealed_vault.format,
-                        ))
+            let format = Format::detect(ifile, m.value_of("format"))?;
+            let yml = load_yml(ifile.to_string())?;
+            let jobs = m.value_of("jobs").unwrap().parse()?; // clap validates this
+
+            let plain_secrets = unseal_file(&keyring, format, &yml, jobs)?;
+            let replacefn = |val: &mut String| Ok(plain_secrets.get(val.as_str()).cloned());
+
+            // Manifests which already hold Secrets are decrypted in place.
+            let secrets = match format {
+                Format::Yaml => {
+                    k8s::decrypt_secrets(&yaml::YamlFile::parse(yml.clone())?, &replacefn)?
+                }
+                _ => None,
+            };
+            match secrets {
+                Some(secrets) => print!("{}", secrets),
+                None => {
+                    let name = m.value_of("name").ok_or_else(|| {
+                        format_err!("--name is required unless the file holds a Secret manifest")
                     })?;
-                    Ok(Some(unsealed_vault.plain_secret))
+                    let variables = format.variables(
+                        format.traverse(&yml, &replacefn)?,
+                        m.value_of("separator").unwrap_or("_"),
+                        !m.is_present("preserve_case"),
+                    )?;
+                    print!(
+                        "{}",
+                        k8s::secret_manifest(
+                            name,
+                            m.value_of("namespace"),
+                            &variables,
+                            m.is_present("string_data"),
+                        )?
+                    )
+                }
+            }
+        }
+        ("helm", Some(m)) => {
+            let replacefn = |val: &mut String| unseal_value(&keyring, val);
+
+            match m.subcommand() {
+                ("downloader", Some(m)) => {
+                    let url = m.value_of("url").unwrap(); // clap handles this;
+                    let ifile = url
+                        .strip_prefix(HELM_SCHEME)
+                        .ok_or_else(|| format_err!("{} is not a {} URI", url, HELM_SCHEME))?;
+                    let format = Format::detect(ifile, None)?;
+                    let yml = load_yml(ifile.to_string())
+                        .context(format!("Could not read {}", ifile))?;
+
+                    print!("{}", format.traverse(&yml, &replacefn)?)
+                }
+                ("post-render", Some(_)) => {
+                    let mut manifests = String::new();
+                    io::stdin().read_to_string(&mut manifests)?;
+                    let yml = yaml::YamlFile::parse(manifests)?;
+
+                    print!("{}", k8s::decrypt_manifests(&yml, &replacefn)?)
+                }
+                _ => unreachable!("clap requires a subcommand"),
+            }
+        }
+        ("git-filter", Some(m)) => {
+            let (mode, m) = match m.subcommand() {
+                (mode, Some(m)) => (mode, m),
+                _ => unreachable!("clap requires a subcommand"),
+            };
+            let ifile = m.value_of("file").unwrap(); // clap handles this;
+            let format = Format::detect(ifile, None)?;
+            let mut contents = String::new();
+            io::stdin().read_to_string(&mut contents)?;
+
+            match mode {
+                "smudge" => {
+                    let replacefn = |val: &mut String| unseal_value(&keyring, val);
+                    print!("{}", format.mark(contents, &replacefn)?)
+                }
+                "clean" => {
+                    // Values which did not change keep the source text of the
+                    // staged vault, so cleaning an unchanged file is a no-op.
+                    let vaults = match staged_contents(ifile)? {
+                        Some(staged) => unseal_vaults(&keyring, format, staged)?,
+                        None => HashMap::new(),
+                    };
+                    let sealfn = |value: String| match vaults.get(&value) {
+                        Some(vault) => Ok(vault.clone()),
+                        None => Ok(Sealed::Vault(seal_value(&keyring, value)?.to_string())),
+                    };
+
+                    match format.seal(contents.clone(), &sealfn)? {
+                        Some(sealed) => print!("{}", sealed),
+                        None => print!("{}", contents),
+                    }
+                }
+                _ => unreachable!("clap only accepts clean and smudge"),
+            }
+        }
+        ("git-textconv", Some(m)) => {
+            let ifile = m.value_of("file").unwrap(); // clap handles this;
+            let format = Format::detect(ifile, None)?;
+            let contents = load_yml(ifile.to_string())?;
+            let jobs = m.value_of("jobs").unwrap().parse()?; // clap validates this
+
+            let plain_secrets = unseal_file(&keyring, format, &contents, jobs)?;
+            let key = if m.is_present("redact") {
+                Some(redact_key()?)
+            } else {
+                None
+            };
+            let replacefn = |val: &mut String| match (plain_secrets.get(val.as_str()), &key) {
+                (Some(plain_secret), Some(key)) => Ok(Some(redacted(key, plain_secret))),
+                (plain_secret, _) => Ok(plain_secret.cloned()),
+            };
+
+            print!("{}", format.traverse(&contents, &replacefn)?)
+        }
+        ("git-merge", Some(m)) => {
+            let load = |file: &str| yaml::YamlFile::parse(load_yml(file.to_string())?);
+            let base = load(m.value_of("base").unwrap())?; // clap handles this;
+            let ours = m.value_of("ours").unwrap();
+            let theirs = load(m.value_of("theirs").unwrap())?;
+
+            let unsealfn = |val: &str| unseal_value(&keyring, val);
+            let sealfn = |value: String| Ok(seal_value(&keyring, value)?.to_string());
+
+            let merged = merge::merge(&base, &load(ours)?, &theirs, &unsealfn, &sealfn)?;
+            write_in_place(ours, &merged)?;
+        }
+        ("lint", Some(m)) => {
+            let allowlist = match m.value_of("allowlist") {
+                Some(file) => lint::Allowlist::parse(&load_yml(file.to_string())?),
+                None if Path::new(DEFAULT_ALLOWLIST).exists() => {
+                    lint::Allowlist::parse(&load_yml(DEFAULT_ALLOWLIST.to_string())?)
+                }
+                None => lint::Allowlist::empty(),
+            };
+            let is_vault = |value: &str| vault::parse(value).is_ok();
+
+            let ifiles = m.values_of("file").unwrap(); // clap handles this;
+
+            let mut findings = vec![];
+            for ifile in ifiles {
+                let yml = yaml::YamlFile::parse(load_yml(ifile.to_string())?)
+                    .context(format!("Could not parse {}", ifile))?;
+                findings.extend(lint::lint(ifile, &yml, &is_vault, &allowlist));
+            }
+
+            match m.value_of("output_format") {
+                Some("json") => println!("{}", serde_json::to_string_pretty(&findings)?),
+                _ => {
+                    for finding in &findings {
+                        println!(
+                            "{}:{}: {} looks like a plaintext secret ({})",
+                            finding.file, finding.line, finding.path, finding.rule
+                        );
+                    }
+                }
+            }
+            if !findings.is_empty() {
+                exit(1);
+            }
+        }
+        ("hook", Some(m)) => match m.subcommand() {
+            ("install", Some(m)) => {
+                let hook_path = git_path("hooks/pre-commit")?;
+                if hook_path.exists() && !m.is_present("force") {
+                    let hook = fs::read_to_string(&hook_path)?;
+                    if !hook::is_own_hook(&hook) {
+                        return Err(format_err!(
+                            "{} exists already, use --force to replace it",
+                            hook_path.display()
+                        ));
+                    }
+                }
+                if let Some(hooks_dir) = hook_path.parent() {
+                    fs::create_dir_all(hooks_dir)?;
+                }
+                fs::write(&hook_path, hook::pre_commit_hook(&culper_command()))
+                    .context(format!("Could not write {}", hook_path.display()))?;
+                make_executable(&hook_path)?;
+            }
+            ("pre-commit", Some(_)) => {
+                let config = if Path::new(hook::REPO_CONFIG_FILE).exists() {
+                    hook::RepoConfig::parse(&load_yml(hook::REPO_CONFIG_FILE.to_string())?)?
+                } else {
+                    hook::RepoConfig::default()
+                };
+
+                let recipients = current_recipients(&ctx)?;
+
+                let mut problems = vec![];
+                for ifile in staged_files()? {
+                    if !hook::is_yaml(&ifile) {
+                        continue;
+                    }
+                    let staged = match staged_contents(&ifile)? {
+                        Some(staged) => staged,
+                        None => continue,
+                    };
+                    let yml = match yaml::YamlFile::parse(staged) {
+                        Ok(yml) => yml,
+                        Err(e) => {
+                            eprintln!("Skipping {}, it could not be parsed: {}", ifile, e);
+                            continue;
+                        }
+                    };
+
+                    let is_vault = |value: &str| vault::parse(value).is_ok();
+                    for (line, path) in hook::plaintext_secrets(&yml, &config, &is_vault) {
+                        problems.push(format!("{}:{}: {} is not encrypted", ifile, line, path));
+                    }
+
+                    for scalar in yml.scalars().iter().filter(|scalar| !scalar.is_key) {
+                        let sealed_vault = match vault::parse(&scalar.value) {
+                            Ok(sealed_vault) => sealed_vault,
+                            Err(_) => continue,
+                        };
+                        let vault_recipients = commands::recipients(&sealed_vault.secret)?;
+                        for tpk in &recipients {
+                            if !key_ids(tpk).iter().any(|id| vault_recipients.contains(id)) {
+                                problems.push(format!(
+                                    "{}:{}: {} cannot be decrypted by {}",
+                                    ifile,
+                                    yml.line(scalar),
+                                    yaml::display_path(&scalar.path),
+                                    tpk.fingerprint()
+                                ));
+                            }
+                        }
+                    }
+                }
+
+                if !problems.is_empty() {
+                    for problem in &problems {
+                        eprintln!("{}", problem);
+                    }
+                    eprintln!("Commit rejected by culper.");
+                    exit(1);
+                }
+            }
+            _ => unreachable!("clap requires a subcommand"),
+        },
+        ("git-init", Some(m)) => {
+            let command = culper_command();
+            git_config("filter.culper.clean", &format!("{} git-filter clean %f", command))?;
+            git_config("filter.culper.smudge", &format!("{} git-filter smudge %f", command))?;
+            git_config("filter.culper.required", "true")?;
+            git_config("diff.culper.textconv", &format!("{} git-textconv --redact", command))?;
+            git_config("merge.culper.name", "culper merge driver")?;
+            git_config("merge.culper.driver", &format!("{} git-merge %O %A %B", command))?;
+            redact_key()?;
+
+            let patterns: Vec<_> = m.values_of("pattern").unwrap().collect(); // clap handles this;
+            add_git_attributes(&patterns, "filter=culper diff=culper merge=culper")?;
+        }
+        ("edit", Some(m)) => {
+            let ifile = m.value_of("file").unwrap(); // clap handles this;
+            let yml = yaml::YamlFile::parse(load_yml(ifile.to_string())?)?;
+
+            // Decrypt every vault and mark it to be encrypted again.
+            let mut unsealed = HashMap::new();
+            let mut replacements = vec![];
+            for (index, scalar) in yml.scalars().iter().enumerate() {
+                if scalar.is_key {
+                    continue;
+                }
+                if let Some(plain_secret) = unseal_value(&keyring, &scalar.value)? {
+                    unsealed.insert(
+                        (scalar.document, scalar.path.clone()),
+                        (plain_secret.clone(), scalar.value.clone(), yml.scalar_source(scalar)),
+                    );
+                    replacements.push((index, plain_secret));
+                }
+            }
+            let decrypted = yml.mark(&replacements);
+
+            let mut edited = decrypted.clone();
+            let edited_yml = loop {
+                edited = edit_in_editor(&edited)?;
+                match yaml::YamlFile::parse(edited.clone()) {
+                    Ok(edited_yml) => break edited_yml,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        if !promptly::prompt_default("Edit again?", true) {
+                            return Err(e);
+                        }
+                    }
+                }
+            };
+
+            if edited == decrypted {
+                eprintln!("No changes.");
+                return Ok(());
+            }
+
+            // Values which did not change keep their ciphertext, written
+            // as it was.
+            let mut replacements = vec![];
+            let mut sources = vec![];
+            for (index, scalar) in edited_yml.scalars().iter().enumerate() {
+                if !scalar.is_marked() {
+                    continue;
+                }
+                match unsealed.get(&(scalar.document, scalar.path.clone())) {
+                    Some((plain_secret, _, Some(text))) if plain_secret == &scalar.value => {
+                        sources.push((index, text.clone()))
+                    }
+                    Some((plain_secret, vault, None)) if plain_secret == &scalar.value => {
+                        replacements.push((index, vault.clone()))
+                    }
+                    _ => replacements.push((
+                        index,
+                        seal_value(&keyring, scalar.value.clone())?.to_string(),
+                    )),
+                }
+            }
+
+            write_in_place(ifile, &edited_yml.unmark(&replacements, &sources))?;
+        }
+        ("seal", Some(m)) => {
+            let ifile = m.value_of("file").unwrap(); // clap handles this;
+            let format = Format::detect(ifile, m.value_of("format"))?;
+            let contents = load_yml(ifile.to_string())?;
+
+            let sealfn =
+                |value: String| Ok(Sealed::Vault(seal_value(&keyring, value)?.to_string()));
+
+            match format.seal(contents, &sealfn)? {
+                Some(sealed) => write_in_place(ifile, &sealed)?,
+                None => eprintln!("No values {}.", format.marker()),
+            }
+        }
+        ("rekey", Some(m)) => {
+            let recipients: Vec<_> = current_recipients(&ctx)?
+                .iter()
+                .map(|tpk| (tpk.fingerprint(), key_ids(tpk)))
+                .collect();
+            // Returns the keys a vault lacks and the key ids it should no
+            // longer be sealed for, if any.
+            let changes = |value: &str| -> Result<Option<(Vec<String>, Vec<String>)>, Error> {
+                let sealed_vault = match vault::parse(value) {
+                    Ok(sealed_vault) => sealed_vault,
+                    Err(_) => return Ok(None),
+                };
+                let vault_recipients = commands::recipients(&sealed_vault.secret)?;
+                let missing: Vec<_> = recipients
+                    .iter()
+                    .filter(|(_, ids)| !ids.iter().any(|id| vault_recipients.contains(id)))
+                    .map(|(fingerprint, _)| fingerprint.to_string())
+                    .collect();
+                let removed: Vec<_> = vault_recipients
+                    .iter()
+                    .filter(|id| !recipients.iter().any(|(_, ids)| ids.contains(id)))
+                    .map(|id| id.to_string())
+                    .collect();
+                if missing.is_empty() && removed.is_empty() {
+                    return Ok(None);
                 }
-                _ => Ok(None),
+                Ok(Some((missing, removed)))
             };
 
-            let uncrypted_yml = yaml::traverse_yml(&yml.as_mapping().unwrap(), &replacefn)?;
-            println!("{}", serde_yaml::to_string(&uncrypted_yml)?)
+            let jobs = m.value_of("jobs").unwrap().parse()?; // clap validates this
+
+            for ifile in m.values_of("files").unwrap() {
+                let format = Format::detect(ifile, m.value_of("format"))?;
+                let contents = load_yml(ifile.to_string())?;
+
+                if m.is_present("dry-run") {
+                    for (path, value) in format.values(contents)? {
+                        if let Some((missing, removed)) = changes(&value)? {
+                            let mut reasons = vec![];
+                            if !missing.is_empty() {
+                                reasons.push(format!("add {}", missing.join(", ")));
+                            }
+                            if !removed.is_empty() {
+                                reasons.push(format!("remove {}", removed.join(", ")));
+                            }
+                            println!(
+                                "{}: {} ({})",
+                                ifile,
+                                yaml::display_path(&path),
+                                reasons.join("; ")
+                            );
+                        }
+                    }
+                    continue;
+                }
+
+                let mut stale = vec![];
+                for (_, value) in format.values(contents.clone())? {
+                    if changes(&value)?.is_some() {
+                        stale.push(value);
+                    }
+                }
+                let plain_secrets = unseal_parallel(&keyring, stale, jobs)?;
+
+                let rekeyed = RefCell::new(0);
+                let rekeyfn = |val: &mut String| {
+                    let plain_secret = match plain_secrets.get(val.as_str()) {
+                        Some(plain_secret) => plain_secret.clone(),
+                        None => return Ok(None),
+                    };
+                    *rekeyed.borrow_mut() += 1;
+                    Ok(Some(seal_value(&keyring, plain_secret)?.to_string()))
+                };
+                let contents = format.traverse(&contents, &rekeyfn)?;
+
+                let rekeyed = rekeyed.into_inner();
+                if rekeyed > 0 {
+                    write_in_place(ifile, &contents)?;
+                }
+                eprintln!("Re-keyed {} vaults of {}.", rekeyed, ifile);
+            }
+        }
+        ("get", Some(m)) => {
+            let ifile = m.value_of("file").unwrap(); // clap handles this;
+            let path = yaml::parse_path(m.value_of("path").unwrap())?;
+            let yml = yaml::YamlFile::parse(load_yml(ifile.to_string())?)?;
+
+            let scalar = yml.get(&path)?;
+            match unseal_value(&keyring, &scalar.value)? {
+                Some(plain_secret) => print!("{}", plain_secret),
+                None => print!("{}", scalar.value),
+            }
         }
         (store_arg @ "target", Some(m)) | (store_arg @ "admin", Some(m)) => {
             let store = Store::open(&ctx, &format!("{}s", store_arg))
@@ -434,6 +899,590 @@ fn real_main() -> Result<(), failure::Error> {
     return Ok(());
 }
 
+fn store_tpks(ctx: &Context, name: &str) -> Result<Vec<TPK>, Error> {
+    let store = Store::open(ctx, name).context("Failed to open the store")?;
+    let tpks: Result<Vec<_>, _> = store
+        .iter()?
+        .map(|(_, _, binding)| binding.tpk())
+        .collect();
+    Ok(tpks?)
+}
+
+/// Creates a new key with a primary key for certification plus a signing
+/// and an encryption subkey, optionally protected by a passphrase.
+fn generate_key(name: &str, with_passphrase: bool) -> Result<TPK, Error> {
+    let mut builder = TPKBuilder::default()
+        .set_cipher_suite(CipherSuite::Cv25519)
+        .add_userid(name)
+        .add_signing_subkey()
+        .add_encryption_subkey();
+
+    if with_passphrase {
+        if let Some(passphrase) = new_passphrase()? {
+            builder = builder.set_password(Some(passphrase.into()));
+        }
+    }
+
+    let (tpk, _revocation) = builder.generate()?;
+    Ok(tpk)
+}
+
+/// Whether there is a terminal to ask for passwords on, which install
+/// scripts and CI jobs lack.
+#[cfg(unix)]
+fn has_terminal() -> bool {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .is_ok()
+}
+
+#[cfg(not(unix))]
+fn has_terminal() -> bool {
+    true
+}
+
+/// Asks for a password on the terminal, as stdin may hold the input of
+/// the command.
+fn read_password(prompt: &str) -> Result<String, Error> {
+    Ok(rpassword::read_password_from_tty(Some(prompt))
+        .context("Could not read password from the terminal.")?)
+}
+
+/// Asks for a new passphrase twice. Returns `None` if it is left empty.
+fn new_passphrase() -> Result<Option<String>, Error> {
+    let passphrase = read_password("Enter new passphrase (leave empty for none): ")?;
+    if passphrase.is_empty() {
+        return Ok(None);
+    }
+    let repeated = read_password("Repeat passphrase: ")?;
+    if passphrase != repeated {
+        return Err(format_err!("The passphrases do not match"));
+    }
+    Ok(Some(passphrase))
+}
+
+/// Calls `f` with the algorithm and the secret key material of the primary
+/// key and of every subkey which has one.
+fn map_secret_keys<F>(tpk: TPK, mut f: F) -> Result<TPK, Error>
+where
+    F: FnMut(PublicKeyAlgorithm, &mut SecretKey) -> Result<(), Error>,
+{
+    let mut packets: Vec<Packet> = tpk.into_packet_pile().into_children().collect();
+    for packet in packets.iter_mut() {
+        let key = match packet {
+            Packet::SecretKey(key) | Packet::SecretSubkey(key) => key,
+            _ => continue,
+        };
+        let pk_algo = key.pk_algo();
+        if let Some(secret) = key.secret_mut() {
+            f(pk_algo, secret)?;
+        }
+    }
+    Ok(TPK::from_packet_pile(PacketPile::from_packets(packets))?)
+}
+
+/// Parses our private key and unlocks the secret key material of the
+/// primary key and of every subkey, each with its own algorithm. The
+/// password is asked for once, and again only for subkeys it does not
+/// unlock.
+fn unlock_priv_key() -> Result<TPK, Error> {
+    let priv_tpk = TPK::from_bytes(priv_key.as_bytes())?;
+
+    let ask = |prompt: &str| -> Result<_, Error> { Ok(Some(read_password(prompt)?.into())) };
+
+    let mut password = None;
+    let mut has_secret = false;
+    let priv_tpk = map_secret_keys(priv_tpk, |pk_algo, secret| {
+        has_secret = true;
+        if !secret.is_encrypted() {
+            return Ok(());
+        }
+
+        let reused = password.is_some();
+        if !reused {
+            password = ask("Enter password to decrypt private key: ")?;
+        }
+        if secret
+            .decrypt_in_place(pk_algo, password.as_ref().unwrap())
+            .is_err()
+        {
+            // Subkeys may be protected by another password than the primary key.
+            if !reused {
+                return Err(format_err!(
+                    "Could not unlock private key, is the password correct?"
+                ));
+            }
+            password = ask("Enter password to decrypt subkey: ")?;
+            secret
+                .decrypt_in_place(pk_algo, password.as_ref().unwrap())
+                .context("Could not unlock subkey, is the password correct?")?;
+        }
+        Ok(())
+    })?;
+
+    if !has_secret {
+        return Err(format_err!("Could not access secret key"));
+    }
+    Ok(priv_tpk)
+}
+
+/// Encrypts the unlocked secret key material of every key with
+/// `passphrase`, using an iterated and salted S2K.
+fn lock_secret_keys(tpk: TPK, passphrase: String) -> Result<TPK, Error> {
+    let password = passphrase.into();
+    map_secret_keys(tpk, |_, secret| {
+        if !secret.is_encrypted() {
+            // The default S2K hashes the salted passphrase iteratively.
+            secret.encrypt_in_place(&password)?;
+        }
+        Ok(())
+    })
+}
+
+/// Serializes a key including its secret key material, ASCII armored.
+fn armored_secret_key(tpk: TPK) -> Result<Vec<u8>, Error> {
+    let mut key_bytes = vec![];
+    {
+        let mut w =
+            Writer::new(&mut key_bytes, Kind::SecretKey, &[]).context("Initializing Writer failed")?;
+        tpk.into_tsk()
+            .serialize(&mut w)
+            .context("Serializing private key into TSK failed")?;
+    }
+    Ok(key_bytes)
+}
+
+/// Writes the private key file, readable by its owner only. The key goes
+/// to a temporary file first, so the old key survives a failed write.
+fn write_priv_key(path: &Path, key_bytes: &[u8]) -> Result<(), Error> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    // Temporary files are only readable by their owner already.
+    let mut tmp_file = NamedTempFile::new_in(dir).context("Failed to create temporary file")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tmp_file
+            .as_file()
+            .set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    tmp_file.write_all(key_bytes)?;
+    tmp_file.as_file().sync_all()?;
+    tmp_file
+        .persist(path)
+        .context(format!("Could not write private key {}", path.display()))?;
+
+    Ok(())
+}
+
+/// The keys vaults are sealed and unsealed with, loaded on first use and
+/// shared by every vault of a run, so the private key is parsed and
+/// unlocked only once.
+struct Keyring<'a> {
+    ctx: &'a Context,
+    priv_tpk: RefCell<Option<TPK>>,
+    unseal_tpks: RefCell<Option<Vec<TPK>>>,
+    seal_tpks: RefCell<Option<Vec<TPK>>>,
+}
+
+impl<'a> Keyring<'a> {
+    fn new(ctx: &'a Context) -> Keyring<'a> {
+        Keyring {
+            ctx,
+            priv_tpk: RefCell::new(None),
+            unseal_tpks: RefCell::new(None),
+            seal_tpks: RefCell::new(None),
+        }
+    }
+
+    /// Our private key with its secret key material unlocked.
+    fn priv_tpk(&self) -> Result<TPK, Error> {
+        if let Some(priv_tpk) = &*self.priv_tpk.borrow() {
+            return Ok(priv_tpk.clone());
+        }
+        let priv_tpk = unlock_priv_key()?;
+        *self.priv_tpk.borrow_mut() = Some(priv_tpk.clone());
+        Ok(priv_tpk)
+    }
+
+    /// The keys whose signatures are accepted on vaults.
+    fn unseal_tpks(&self) -> Result<Vec<TPK>, Error> {
+        if let Some(tpks) = &*self.unseal_tpks.borrow() {
+            return Ok(tpks.clone());
+        }
+        let mut tpks = store_tpks(self.ctx, "owner")?;
+        tpks.extend(store_tpks(self.ctx, "admins")?);
+        tpks.push(self.priv_tpk()?);
+        *self.unseal_tpks.borrow_mut() = Some(tpks.clone());
+        Ok(tpks)
+    }
+
+    /// The keys vaults are sealed for, see `current_recipients`.
+    fn seal_tpks(&self) -> Result<Vec<TPK>, Error> {
+        if let Some(tpks) = &*self.seal_tpks.borrow() {
+            return Ok(tpks.clone());
+        }
+        let tpks = current_recipients(self.ctx)?;
+        *self.seal_tpks.borrow_mut() = Some(tpks.clone());
+        Ok(tpks)
+    }
+}
+
+/// Unseals `value` if it is a vault.
+fn unseal_value(keyring: &Keyring, value: &str) -> Result<Option<String>, Error> {
+    if vault::parse(value).is_err() {
+        return Ok(None);
+    }
+    unseal_with(value, &keyring.unseal_tpks()?, &keyring.priv_tpk()?)
+}
+
+/// Unseals every distinct vault on `jobs` threads and returns the plain
+/// secrets by vault.
+fn unseal_parallel(
+    keyring: &Keyring,
+    mut vaults: Vec<String>,
+    jobs: usize,
+) -> Result<HashMap<String, String>, Error> {
+    vaults.sort();
+    vaults.dedup();
+    if vaults.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    // Unlocking the key may prompt for its passphrase, so it has to happen
+    // before the workers start.
+    let tpks = Arc::new(keyring.unseal_tpks()?);
+    let priv_tpk = Arc::new(keyring.priv_tpk()?);
+    let queue = Arc::new(Mutex::new(vaults));
+
+    let workers: Vec<_> = (0..jobs)
+        .map(|_| {
+            let (tpks, priv_tpk, queue) = (tpks.clone(), priv_tpk.clone(), queue.clone());
+            thread::spawn(move || -> Result<Vec<(String, String)>, Error> {
+                let mut plain_secrets = vec![];
+                loop {
+                    let value = match queue.lock().unwrap().pop() {
+                        Some(value) => value,
+                        None => return Ok(plain_secrets),
+                    };
+                    if let Some(plain_secret) = unseal_with(&value, &tpks, &priv_tpk)? {
+                        plain_secrets.push((value, plain_secret));
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let mut plain_secrets = HashMap::new();
+    for worker in workers {
+        let unsealed = worker
+            .join()
+            .map_err(|_| format_err!("Decryption thread panicked"))??;
+        plain_secrets.extend(unsealed);
+    }
+    Ok(plain_secrets)
+}
+
+/// Unseals the vaults among the values of `source` on `jobs` threads.
+/// Returns the plain secrets by vault.
+fn unseal_file(
+    keyring: &Keyring,
+    format: Format,
+    source: &str,
+    jobs: usize,
+) -> Result<HashMap<String, String>, Error> {
+    let vaults = format
+        .values(source.to_owned())?
+        .into_iter()
+        .map(|(_, value)| value)
+        .filter(|value| vault::parse(value).is_ok())
+        .collect();
+    unseal_parallel(keyring, vaults, jobs)
+}
+
+/// Unseals `value` with the given keys if it is a vault.
+fn unseal_with(value: &str, tpks: &[TPK], priv_tpk: &TPK) -> Result<Option<String>, Error> {
+    match vault::parse(value) {
+        Ok(vault) => {
+            let unsealed_vault = vault.unseal(&|sealed_vault: SealedVault| {
+                let data = commands::decrypt(
+                    sealed_vault.secret,
+                    1,
+                    tpks.to_vec(),
+                    vec![priv_tpk.clone()],
+                )?;
+                Ok(vault::UnsealedVault::new(
+                    String::from_utf8(data)?,
+                    sealed_vault.format,
+                ))
+            })?;
+            Ok(Some(unsealed_vault.plain_secret))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Seals a value for all targets and admins as well as ourselves.
+fn seal_value(keyring: &Keyring, value: String) -> Result<SealedVault, Error> {
+    let recipients = keyring.seal_tpks()?;
+    let priv_tpk = vec![keyring.priv_tpk()?];
+
+    let vault = vault::UnsealedVault::new(value, vault::EncryptionFormat::GPG_KEY);
+    let sealed_vault = vault.seal(&move |vault: vault::UnsealedVault| {
+        let secret_bytes = vault.plain_secret.as_bytes();
+        let data =
+            commands::encrypt(secret_bytes.to_vec(), recipients.clone(), priv_tpk.clone())?;
+
+        Ok(vault::SealedVault::new(data, vault.format))
+    })?;
+
+    Ok(sealed_vault)
+}
+
+/// Replaces culper with the command, so signals reach it directly. Only
+/// returns if the command could not be started.
+#[cfg(unix)]
+fn exec_command(mut command: Command, program: &str) -> Result<(), Error> {
+    use std::os::unix::process::CommandExt;
+    let error = command.exec();
+    Err(Error::from(error)
+        .context(format!("Could not start {}", program))
+        .into())
+}
+
+/// Runs the command and exits with its exit code.
+#[cfg(not(unix))]
+fn exec_command(mut command: Command, program: &str) -> Result<(), Error> {
+    let status = command
+        .status()
+        .context(format!("Could not start {}", program))?;
+    exit(status.code().unwrap_or(1));
+}
+
+/// Returns the source texts of the vaults of `source` by their plain
+/// secret.
+fn unseal_vaults(
+    keyring: &Keyring,
+    format: Format,
+    source: String,
+) -> Result<HashMap<String, Sealed>, Error> {
+    let mut vaults = HashMap::new();
+    for (value, text) in format.sources(source)? {
+        if let Some(plain_secret) = unseal_value(keyring, &value)? {
+            vaults.insert(plain_secret, text);
+        }
+    }
+    Ok(vaults)
+}
+
+/// Replaces a plain secret by a stable hash, so changes stay visible in
+/// diffs without showing the secret. The hash is keyed, so guessed secrets
+/// cannot be checked against it outside of the repository.
+fn redacted(key: &[u8], plain_secret: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.input(plain_secret.as_bytes());
+    let digest: String = mac
+        .result()
+        .code()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("<redacted hmac-sha256:{}>", &digest[..16])
+}
+
+/// Returns the secret of the repository which keys redacted values,
+/// creating it on first use.
+fn redact_key() -> Result<Vec<u8>, Error> {
+    if let Some(key) = git_config_value(REDACT_KEY)? {
+        return Ok(decode(&key).context(format!("Invalid git config {}", REDACT_KEY))?);
+    }
+    let key: [u8; 32] = rand::random();
+    git_config(REDACT_KEY, &encode(&key))?;
+    Ok(key.to_vec())
+}
+
+/// Returns the contents of a file in the git index, if it is staged.
+fn staged_contents(file_path: &str) -> Result<Option<String>, Error> {
+    let output = Command::new("git")
+        .args(&["cat-file", "blob", &format!(":{}", file_path)])
+        .stderr(Stdio::null())
+        .output()
+        .context("Could not run git")?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8(output.stdout)?))
+}
+
+/// Returns the staged files which are added, copied or modified.
+fn staged_files() -> Result<Vec<String>, Error> {
+    let output = Command::new("git")
+        .args(&["diff", "--cached", "--name-only", "--diff-filter=ACM", "-z"])
+        .output()
+        .context("Could not run git")?;
+    if !output.status.success() {
+        return Err(format_err!("Could not list staged files"));
+    }
+    Ok(String::from_utf8(output.stdout)?
+        .split('\0')
+        .filter(|file| !file.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Resolves a path inside the `.git` directory.
+fn git_path(path: &str) -> Result<PathBuf, Error> {
+    let output = Command::new("git")
+        .args(&["rev-parse", "--git-path", path])
+        .output()
+        .context("Could not run git")?;
+    if !output.status.success() {
+        return Err(format_err!("Not inside a git repository"));
+    }
+    Ok(PathBuf::from(String::from_utf8(output.stdout)?.trim_end()))
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Returns the keys every vault is sealed for: all targets and admins as
+/// well as ourselves.
+fn current_recipients(ctx: &Context) -> Result<Vec<TPK>, Error> {
+    let mut recipients = store_tpks(ctx, "targets")?;
+    recipients.extend(store_tpks(ctx, "admins")?);
+    recipients.push(TPK::from_bytes(priv_key.as_bytes())?);
+    Ok(recipients)
+}
+
+/// Returns the ids of the primary key and all subkeys.
+fn key_ids(tpk: &TPK) -> Vec<KeyID> {
+    let mut ids = vec![tpk.fingerprint().to_keyid()];
+    ids.extend(tpk.subkeys().map(|skb| skb.subkey().fingerprint().to_keyid()));
+    ids
+}
+
+/// The command line to run culper with the current home directory.
+fn culper_command() -> String {
+    match matches.value_of("home") {
+        Some(home) => format!("culper --home {}", shell_quote(home)),
+        None => "culper".to_owned(),
+    }
+}
+
+/// Returns the value of a git config key, if it is set.
+fn git_config_value(key: &str) -> Result<Option<String>, Error> {
+    let output = Command::new("git")
+        .args(&["config", "--get", key])
+        .output()
+        .context("Could not run git")?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8(output.stdout)?.trim_end().to_owned()))
+}
+
+fn git_config(key: &str, value: &str) -> Result<(), Error> {
+    let status = Command::new("git")
+        .args(&["config", key, value])
+        .status()
+        .context("Could not run git")?;
+    if !status.success() {
+        return Err(format_err!("Could not set git config {}", key));
+    }
+    Ok(())
+}
+
+/// Adds `attributes` to the patterns in `.gitattributes` which do not have
+/// them yet.
+fn add_git_attributes(patterns: &[&str], attributes: &str) -> Result<(), Error> {
+    let path = Path::new(".gitattributes");
+    let mut contents = if path.exists() {
+        fs::read_to_string(path).context("Could not read .gitattributes")?
+    } else {
+        String::new()
+    };
+
+    for pattern in patterns {
+        let line = format!("{} {}", pattern, attributes);
+        if contents.lines().any(|existing| existing.trim() == line) {
+            continue;
+        }
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        contents.push_str(&line);
+        contents.push('\n');
+    }
+    write_in_place(".gitattributes", &contents)
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Atomically replaces the contents of a file, keeping its permissions.
+fn write_in_place(file_path: &str, contents: &str) -> Result<(), Error> {
+    let path = Path::new(file_path);
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let mut tmp_file = NamedTempFile::new_in(dir).context("Failed to create temporary file")?;
+    tmp_file.write_all(contents.as_bytes())?;
+    if let Ok(metadata) = fs::metadata(path) {
+        fs::set_permissions(tmp_file.path(), metadata.permissions())?;
+    }
+    tmp_file
+        .persist(path)
+        .context(format!("Could not write {}", file_path))?;
+
+    Ok(())
+}
+
+/// Lets the user edit `contents` in `$EDITOR` and returns the result.
+fn edit_in_editor(contents: &str) -> Result<String, Error> {
+    let mut tmp_file = tempfile::Builder::new()
+        .prefix("culper-")
+        .suffix(".yml")
+        .tempfile()
+        .context("Failed to create temporary file")?;
+    tmp_file.write_all(contents.as_bytes())?;
+    tmp_file.flush()?;
+
+    let editor = env::var("EDITOR").unwrap_or_else(|_| "vi".to_owned());
+    let mut editor_args = editor.split_whitespace();
+    let program = editor_args
+        .next()
+        .ok_or_else(|| format_err!("$EDITOR is empty"))?;
+    let status = Command::new(program)
+        .args(editor_args)
+        .arg(tmp_file.path())
+        .status()
+        .context(format!("Could not start editor {}", editor))?;
+    if !status.success() {
+        return Err(format_err!("Editor {} exited with {}", editor, status));
+    }
+
+    let mut edited = String::new();
+    File::open(tmp_file.path())?.read_to_string(&mut edited)?;
+    Ok(edited)
+}
+
 fn list_bindings(store: &Store, domain: &str, name: &str) -> Result<(), failure::Error> {
     if store.iter()?.count() == 0 {
         println!("No {} available.", name);
@@ -455,15 +1504,14 @@ fn list_bindings(store: &Store, domain: &str, name: &str) -> Result<(), failure:
     Ok(())
 }
 
-fn load_yml(file_path: String) -> Result<serde_yaml::Value, Error> {
+fn load_yml(file_path: String) -> Result<String, Error> {
     let mut contents = String::new();
     let mut file = File::open(file_path)?;
 
     file.read_to_string(&mut contents)
         .or_else(|_| Err(format_err!("Could not parse result to YAML.")))?;
 
-    serde_yaml::from_str::<serde_yaml::Value>(&contents)
-        .or_else(|_| Err(format_err!("Could not parse result to YAML.")))
+    Ok(contents)
 }
 
 fn main() {
@@ -481,6 +1529,16 @@ fn main() {
 
 mod commands;
 mod culper_cli;
+mod dotenv;
+mod format;
+mod hook;
+mod json;
+mod k8s;
+mod lint;
+mod merge;
+#[cfg(test)]
+mod testing;
+mod toml_file;
 mod yaml;
 
 #[cfg(test)]
@@ -1,5 +1,8 @@
 use clap::{App, AppSettings, Arg, SubCommand};
 
+use crate::dotenv;
+use crate::format;
+
 pub fn build() -> App<'static, 'static> {
     App::new("culper")
         .version(clap::crate_version!())
@@ -14,11 +17,41 @@ pub fn build() -> App<'static, 'static> {
         .subcommand(
             SubCommand::with_name("setup")
                 .about("Start setup for culper.")
+                .after_help(
+                    "Reads an existing private key from stdin, e.g. from \
+                     gpg -a --export-secret-keys <your-key-id>, unless --generate is given.",
+                )
                 .arg(
                     Arg::with_name("name")
                         .long("name")
                         .required(true)
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("generate")
+                        .long("generate")
+                        .help("Generates a new key instead of reading one from stdin"),
+                )
+                .arg(
+                    Arg::with_name("passphrase")
+                        .long("passphrase")
+                        .requires("generate")
+                        .help("Asks for a passphrase to protect the generated key with"),
+                )
+                .arg(
+                    Arg::with_name("no_passphrase")
+                        .long("no-passphrase")
+                        .conflicts_with("passphrase")
+                        .help("Stores a key read from stdin without asking for a passphrase"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("key")
+                .about("Manages the private key")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("passwd")
+                        .about("Changes or removes the passphrase of the private key"),
                 ),
         )
         .subcommand(
@@ -30,6 +63,306 @@ pub fn build() -> App<'static, 'static> {
                         .long("file")
                         .takes_value(true)
                         .required(true),
+                )
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("output_format")
+                        .long("output-format")
+                        .possible_values(dotenv::STYLE_NAMES)
+                        .help("Flattens the values into variables named after their paths")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("separator")
+                        .long("separator")
+                        .help("Separates the path segments of variable names, defaults to _")
+                        .requires("output_format")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("preserve_case")
+                        .long("preserve-case")
+                        .help("Keeps the case of variable names instead of upper-casing them")
+                        .requires("output_format"),
+                )
+                .arg(jobs_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("exec")
+                .display_order(13)
+                .about("Runs a command with the decrypted values as environment variables")
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("separator")
+                        .long("separator")
+                        .help("Separates the path segments of variable names, defaults to _")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("preserve_case")
+                        .long("preserve-case")
+                        .help("Keeps the case of variable names instead of upper-casing them"),
+                )
+                .arg(jobs_arg())
+                .arg(
+                    Arg::with_name("command")
+                        .value_name("COMMAND")
+                        .multiple(true)
+                        .required(true)
+                        .last(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("k8s-secret")
+                .display_order(14)
+                .about("Prints a Kubernetes Secret manifest holding the decrypted values")
+                .after_help(
+                    "If the file already holds Secret manifests, the vaults under \
+                     their data and stringData are decrypted instead.",
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .help("Name of the Secret")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("namespace")
+                        .long("namespace")
+                        .help("Namespace of the Secret")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("string_data")
+                        .long("string-data")
+                        .help("Writes the values to stringData instead of base64 encoded to data"),
+                )
+                .arg(
+                    Arg::with_name("separator")
+                        .long("separator")
+                        .help("Separates the path segments of keys, defaults to _")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("preserve_case")
+                        .long("preserve-case")
+                        .help("Keeps the case of keys instead of upper-casing them"),
+                )
+                .arg(jobs_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("helm")
+                .display_order(15)
+                .about("Decrypts values for Helm, used by the culper Helm plugin")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("downloader")
+                        .about("Prints the decrypted file of a culper:// URI")
+                        .arg(Arg::with_name("cert_file").required(true))
+                        .arg(Arg::with_name("key_file").required(true))
+                        .arg(Arg::with_name("ca_file").required(true))
+                        .arg(Arg::with_name("url").value_name("URL").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("post-render")
+                        .about("Decrypts the rendered manifests read from stdin"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("lint")
+                .display_order(35)
+                .about("Finds values of YAML files which look like secrets but are not encrypted")
+                .after_help(
+                    "Exits with 1 if any value is found. Values can be allowed by listing \
+                     their path, e.g. db.password, or file and path, e.g. \
+                     values.yml:db.*, in the allowlist, one per line.",
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .value_name("FILE")
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("allowlist")
+                        .long("allowlist")
+                        .value_name("FILE")
+                        .help("Allowlist to use, defaults to .culper-allowlist if it exists")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output_format")
+                        .long("output-format")
+                        .possible_values(&["text", "json"])
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("hook")
+                .display_order(36)
+                .about("Manages the git pre-commit hook which rejects unencrypted secrets")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("install")
+                        .about("Writes the pre-commit hook of the current repository")
+                        .after_help(
+                            "The hook rejects commits of YAML files with plaintext values at \
+                             the paths listed as secrets in the .culper.toml of the \
+                             repository, e.g. secrets = [\"db.password\", \"*.token\"], and \
+                             of vaults which not all targets and admins can decrypt.",
+                        )
+                        .arg(
+                            Arg::with_name("force")
+                                .long("force")
+                                .help("Replaces an existing pre-commit hook"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("pre-commit")
+                        .about("Checks the staged YAML files, run by the pre-commit hook"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("git-filter")
+                .display_order(40)
+                .about("Decrypts files on checkout and encrypts them on commit, used as git filter")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("clean")
+                        .about("Encrypts the values tagged with !encrypt read from stdin")
+                        .arg(Arg::with_name("file").value_name("FILE").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("smudge")
+                        .about("Decrypts the values read from stdin and tags them with !encrypt")
+                        .arg(Arg::with_name("file").value_name("FILE").required(true)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("git-textconv")
+                .display_order(42)
+                .about("Prints a file with decrypted values, used as git diff textconv")
+                .arg(Arg::with_name("file").value_name("FILE").required(true))
+                .arg(
+                    Arg::with_name("redact")
+                        .long("redact")
+                        .help("Prints a keyed hash of each decrypted value instead of the value"),
+                )
+                .arg(jobs_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("git-merge")
+                .display_order(43)
+                .about("Merges YAML files key by key, used as git merge driver")
+                .arg(Arg::with_name("base").value_name("BASE").required(true))
+                .arg(Arg::with_name("ours").value_name("OURS").required(true))
+                .arg(Arg::with_name("theirs").value_name("THEIRS").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("git-init")
+                .display_order(41)
+                .about("Sets up the culper git drivers for files matching the given patterns")
+                .arg(
+                    Arg::with_name("pattern")
+                        .value_name("PATTERN")
+                        .help("Pattern of .gitattributes, e.g. *.enc.yml")
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("edit")
+                .display_order(12)
+                .about("Edits the decrypted values of a YAML file in $EDITOR")
+                .after_help(
+                    "Decrypted values are tagged with !encrypt. Every value tagged \
+                     with !encrypt is encrypted when the editor exits, values which \
+                     did not change keep their original ciphertext.",
+                )
+                .arg(Arg::with_name("file").value_name("FILE").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("seal")
+                .display_order(21)
+                .about("Encrypts every value of a file tagged with !encrypt")
+                .after_help(
+                    "JSON, TOML and dotenv files have no tags, so their values are \
+                     marked by prefixing them with \"!encrypt \".",
+                )
+                .arg(Arg::with_name("file").value_name("FILE").required(true))
+                .arg(format_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("rekey")
+                .display_order(22)
+                .about("Seals the vaults of files again for the current targets and admins")
+                .arg(
+                    Arg::with_name("files")
+                        .value_name("FILE")
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(
+                    format_arg()
+                        .help("Format of the files, detected from their extensions by default"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Lists the vaults whose recipients would change"),
+                )
+                .arg(jobs_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .display_order(11)
+                .about("Prints the decrypted value at the given path of a YAML file")
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .value_name("KEY.PATH")
+                        .help("Path of the value, e.g. db.password")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set")
+                .display_order(30)
+                .about("Encrypts a value into a file at the given path")
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .value_name("KEY.PATH")
+                        .help("Path of the value, e.g. db.credentials.password")
+                        .takes_value(true)
+                        .required(true),
                 ),
         )
         .subcommand(
@@ -97,3 +430,24 @@ pub fn build() -> App<'static, 'static> {
                 .about("Encrypts a message"),
         )
 }
+
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .long("format")
+        .possible_values(format::FORMAT_NAMES)
+        .help("Format of the file, detected from its extension by default")
+        .takes_value(true)
+}
+
+fn jobs_arg() -> Arg<'static, 'static> {
+    Arg::with_name("jobs")
+        .long("jobs")
+        .short("j")
+        .value_name("N")
+        .default_value("1")
+        .validator(|jobs| match jobs.parse::<usize>() {
+            Ok(jobs) if jobs > 0 => Ok(()),
+            _ => Err("must be a positive number".to_owned()),
+        })
+        .help("Number of vaults decrypted in parallel")
+}
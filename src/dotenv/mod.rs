@@ -0,0 +1,285 @@
+use failure::{Error, ResultExt};
+use std::ops::Range;
+
+use crate::yaml::{display_path, Segment};
+
+/// A `KEY=value` line of a dotenv file.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+    span: Range<usize>,
+}
+
+/// How flattened variables are written out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    Env,
+    Export,
+    Json,
+}
+
+/// Names accepted by `--output-format`.
+pub const STYLE_NAMES: &[&str] = &["env", "export", "json"];
+
+impl Style {
+    pub fn from_name(name: &str) -> Result<Style, Error> {
+        match name {
+            "env" => Ok(Style::Env),
+            "export" => Ok(Style::Export),
+            "json" => Ok(Style::Json),
+            _ => Err(format_err!("Unknown output format {}", name)),
+        }
+    }
+}
+
+/// Parses the entries of a dotenv file. Blank lines, comments and an
+/// `export` in front of a key are allowed.
+pub fn parse(source: &str) -> Result<Vec<Entry>, Error> {
+    let mut entries = vec![];
+    let mut line_start = 0;
+    for (number, line) in source.split('\n').enumerate() {
+        let invalid = || format_err!("Invalid line {} of dotenv file", number + 1);
+        let offset = line_start;
+        line_start += line.len() + 1;
+
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let declaration = trimmed.strip_prefix("export ").unwrap_or(trimmed);
+        let equals = declaration.find('=').ok_or_else(invalid)?;
+        let key = declaration[..equals].trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(invalid());
+        }
+
+        let raw = &declaration[equals + 1..];
+        let value_start = offset + line.len() - raw.trim_start().len();
+        let raw = raw.trim_start();
+        let (value, length) = parse_value(raw).ok_or_else(invalid)?;
+
+        entries.push(Entry {
+            key: key.to_owned(),
+            value,
+            span: value_start..value_start + length,
+        });
+    }
+    Ok(entries)
+}
+
+/// Parses a single-quoted, double-quoted or bare value and returns it
+/// together with the length of its source text.
+fn parse_value(raw: &str) -> Option<(String, usize)> {
+    if let Some(quoted) = raw.strip_prefix('\'') {
+        let end = quoted.find('\'')?;
+        return Some((quoted[..end].to_owned(), end + 2));
+    }
+
+    if let Some(quoted) = raw.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = quoted.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Some((value, i + 2)),
+                '\\' => match chars.next()?.1 {
+                    'n' => value.push('\n'),
+                    'r' => value.push('\r'),
+                    't' => value.push('\t'),
+                    escaped => value.push(escaped),
+                },
+                c => value.push(c),
+            }
+        }
+        return None;
+    }
+
+    let end = raw.find(" #").unwrap_or(raw.len());
+    let value = raw[..end].trim_end();
+    Some((value.to_owned(), value.len()))
+}
+
+pub fn traverse_env<F>(source: &str, f: &F) -> Result<String, Error>
+where
+    F: Fn(&mut String) -> Result<Option<String>, Error>,
+{
+    let mut output = String::with_capacity(source.len());
+    let mut position = 0;
+    for entry in parse(source)? {
+        let new_value = f(&mut entry.value.clone())
+            .with_context(|_| format!("Failed to replace value at {}", entry.key))?;
+        if let Some(new_value) = new_value {
+            output.push_str(&source[position..entry.span.start]);
+            output.push_str(&format_value(&new_value, Style::Env));
+            position = entry.span.end;
+        }
+    }
+    output.push_str(&source[position..]);
+    Ok(output)
+}
+
+/// Sets the value of `path`, which has to be a single key, appending a new
+/// entry if the key does not exist yet.
+pub fn set(source: &str, path: &[Segment], value: &str) -> Result<String, Error> {
+    let key = match path {
+        [Segment::Key(key)] => key,
+        _ => {
+            return Err(format_err!(
+                "Cannot set {}, dotenv files only have plain keys",
+                display_path(path)
+            ))
+        }
+    };
+
+    let value = format_value(value, Style::Env);
+    match parse(source)?.into_iter().find(|entry| &entry.key == key) {
+        Some(entry) => Ok(format!(
+            "{}{}{}",
+            &source[..entry.span.start],
+            value,
+            &source[entry.span.end..]
+        )),
+        None if source.is_empty() || source.ends_with('\n') => {
+            Ok(format!("{}{}={}\n", source, key, value))
+        }
+        None => Ok(format!("{}\n{}={}\n", source, key, value)),
+    }
+}
+
+/// Builds a variable name from a path, e.g. `DB_REPLICAS_0_PASSWORD` for
+/// `db.replicas[0].password`. Characters which are not allowed in variable
+/// names are replaced by the separator, and names starting with a digit
+/// are prefixed with an underscore.
+pub fn variable_name(path: &[Segment], separator: &str, uppercase: bool) -> String {
+    let mut name = path
+        .iter()
+        .map(|segment| match segment {
+            Segment::Key(key) => key
+                .chars()
+                .map(|c| match c {
+                    'A'..='Z' | 'a'..='z' | '0'..='9' | '_' => c.to_string(),
+                    _ => separator.to_owned(),
+                })
+                .collect(),
+            Segment::Index(index) => index.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(separator);
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+
+    if uppercase {
+        name.to_uppercase()
+    } else {
+        name
+    }
+}
+
+/// Writes variables in the given style, one per line.
+pub fn format_variables(variables: &[(String, String)], style: Style) -> Result<String, Error> {
+    if style == Style::Json {
+        let object: serde_json::Map<_, _> = variables
+            .iter()
+            .map(|(name, value)| (name.clone(), serde_json::Value::String(value.clone())))
+            .collect();
+        return Ok(format!("{}\n", serde_json::to_string_pretty(&object)?));
+    }
+
+    let prefix = if style == Style::Export {
+        "export "
+    } else {
+        ""
+    };
+    Ok(variables
+        .iter()
+        .map(|(name, value)| format!("{}{}={}\n", prefix, name, format_value(value, style)))
+        .collect())
+}
+
+fn format_value(value: &str, style: Style) -> String {
+    let is_bare = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_-.,:/@%+=".contains(c));
+
+    match style {
+        _ if is_bare => value.to_owned(),
+        Style::Export => format!("'{}'", value.replace('\'', "'\\''")),
+        _ if !value.contains(['\'', '\n']) => format!("'{}'", value),
+        _ => format!(
+            "\"{}\"",
+            value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::unwrap_vault;
+
+    #[test]
+    fn rewrites_only_vaults() {
+        let source = "# database\nexport DB_USER=app\nDB_PASSWORD=\"VAULT:it's\" # secret\n\
+                      TOKEN='VAULT:a b'\nMULTI=VAULT:x\\ny\n";
+        assert_eq!(
+            traverse_env(source, &|val: &mut String| unwrap_vault(val)).unwrap(),
+            "# database\nexport DB_USER=app\nDB_PASSWORD=\"it's\" # secret\n\
+             TOKEN='a b'\nMULTI='x\\ny'\n"
+        );
+    }
+
+    #[test]
+    fn parses_quoted_values() {
+        let entries = parse("A=\"x\\\"y\\nz\"\nB = 'c # d'\nC=e # f\n").unwrap();
+        let values: Vec<_> = entries
+            .iter()
+            .map(|e| (e.key.as_str(), e.value.as_str()))
+            .collect();
+        assert_eq!(values, vec![("A", "x\"y\nz"), ("B", "c # d"), ("C", "e")]);
+        assert_eq!(
+            parse("A=1\nnot a declaration\n").unwrap_err().to_string(),
+            "Invalid line 2 of dotenv file"
+        );
+    }
+
+    #[test]
+    fn sets_values() {
+        let path = [Segment::Key("B".to_owned())];
+        assert_eq!(set("A=1", &path, "x y").unwrap(), "A=1\nB='x y'\n");
+        assert_eq!(set("B=1 # c\n", &path, "2").unwrap(), "B=2 # c\n");
+    }
+
+    #[test]
+    fn formats_variables() {
+        let path = [
+            Segment::Key("db".to_owned()),
+            Segment::Index(0),
+            Segment::Key("password".to_owned()),
+        ];
+        assert_eq!(variable_name(&path, "_", true), "DB_0_PASSWORD");
+        assert_eq!(variable_name(&path, "__", false), "db__0__password");
+        let path = [
+            Segment::Key("2fa".to_owned()),
+            Segment::Key("api-key.v1".to_owned()),
+        ];
+        assert_eq!(variable_name(&path, "_", true), "_2FA_API_KEY_V1");
+
+        let variables = vec![("A".to_owned(), "it's".to_owned())];
+        assert_eq!(
+            format_variables(&variables, Style::Env).unwrap(),
+            "A=\"it's\"\n"
+        );
+        assert_eq!(
+            format_variables(&variables, Style::Export).unwrap(),
+            "export A='it'\\''s'\n"
+        );
+        assert_eq!(
+            format_variables(&variables, Style::Json).unwrap(),
+            "{\n  \"A\": \"it's\"\n}\n"
+        );
+    }
+}
@@ -0,0 +1,325 @@
+use failure::{Error, ResultExt};
+use std::cell::Cell;
+use std::path::Path;
+
+use crate::dotenv;
+use crate::json;
+use crate::toml_file;
+use crate::yaml::{self, display_path, Segment};
+
+/// Names accepted by `--format`.
+pub const FORMAT_NAMES: &[&str] = &["yaml", "json", "toml", "env"];
+
+/// Prefix which marks string values that should be encrypted in formats
+/// without tags.
+pub const ENCRYPT_PREFIX: &str = "!encrypt ";
+
+/// The vault which replaces a value marked for encryption.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Sealed {
+    /// A new vault, formatted to fit the place of the value.
+    Vault(String),
+    /// The source text of an existing vault as returned by `sources`,
+    /// which is written back as it is.
+    Source(String),
+}
+
+/// A file format which can hold vaults.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Json,
+    Toml,
+    Env,
+}
+
+impl Format {
+    /// Uses the given format name or detects the format from the file
+    /// extension, defaulting to YAML.
+    pub fn detect(file_path: &str, name: Option<&str>) -> Result<Format, Error> {
+        match name {
+            Some("yaml") => Ok(Format::Yaml),
+            Some("json") => Ok(Format::Json),
+            Some("toml") => Ok(Format::Toml),
+            Some("env") => Ok(Format::Env),
+            Some(name) => Err(format_err!("Unknown format {}", name)),
+            None if is_dotenv(file_path) => Ok(Format::Env),
+            None => match Path::new(file_path).extension().and_then(|e| e.to_str()) {
+                Some(extension) if extension.eq_ignore_ascii_case("json") => Ok(Format::Json),
+                Some(extension) if extension.eq_ignore_ascii_case("toml") => Ok(Format::Toml),
+                _ => Ok(Format::Yaml),
+            },
+        }
+    }
+
+    /// Replaces every value for which `f` returns a new value.
+    pub fn traverse<F>(self, source: &str, f: &F) -> Result<String, Error>
+    where
+        F: Fn(&mut String) -> Result<Option<String>, Error>,
+    {
+        match self {
+            Format::Yaml => yaml::traverse_yml(source, f),
+            Format::Json => json::traverse_json(source, f),
+            Format::Toml => toml_file::traverse_toml(source, f),
+            Format::Env => dotenv::traverse_env(source, f),
+        }
+    }
+
+    /// Sets the value at `path`, creating missing parents on the way.
+    pub fn set(self, source: String, path: &[Segment], value: &str) -> Result<String, Error> {
+        match self {
+            Format::Yaml => yaml::YamlFile::parse(source)?.set(path, value),
+            Format::Json => json::set(&source, path, value),
+            Format::Toml => Err(format_err!("Setting values of TOML files is not supported")),
+            Format::Env => dotenv::set(&source, path, value),
+        }
+    }
+
+    /// Replaces every value for which `f` returns a new value and marks it
+    /// for encryption, the counterpart of `seal`.
+    pub fn mark<F>(self, source: String, f: &F) -> Result<String, Error>
+    where
+        F: Fn(&mut String) -> Result<Option<String>, Error>,
+    {
+        match self {
+            Format::Yaml => {
+                let yml = yaml::YamlFile::parse(source)?;
+
+                let mut replacements = vec![];
+                for (index, scalar) in yml.scalars().iter().enumerate() {
+                    if scalar.is_key || scalar.is_empty() {
+                        continue;
+                    }
+                    let new_value = f(&mut scalar.value.clone()).with_context(|_| {
+                        format!("Failed to replace value at {}", display_path(&scalar.path))
+                    })?;
+                    if let Some(new_value) = new_value {
+                        replacements.push((index, new_value));
+                    }
+                }
+                Ok(yml.mark(&replacements))
+            }
+            Format::Json | Format::Toml | Format::Env => {
+                self.traverse(&source, &|val: &mut String| {
+                    Ok(f(val)?.map(|new_value| format!("{}{}", ENCRYPT_PREFIX, new_value)))
+                })
+            }
+        }
+    }
+
+    /// Replaces every value marked for encryption with the result of `f`.
+    /// Returns `None` if no value is marked.
+    pub fn seal<F>(self, source: String, f: &F) -> Result<Option<String>, Error>
+    where
+        F: Fn(String) -> Result<Sealed, Error>,
+    {
+        match self {
+            Format::Yaml => {
+                let yml = yaml::YamlFile::parse(source)?;
+
+                let mut replacements = vec![];
+                let mut sources = vec![];
+                for (index, scalar) in yml.scalars().iter().enumerate() {
+                    if !scalar.is_marked() {
+                        continue;
+                    }
+                    match f(scalar.value.clone())? {
+                        Sealed::Vault(vault) => replacements.push((index, vault)),
+                        Sealed::Source(text) => sources.push((index, text)),
+                    }
+                }
+
+                if replacements.is_empty() && sources.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(yml.unmark(&replacements, &sources)))
+            }
+            Format::Json | Format::Toml | Format::Env => {
+                let marked = Cell::new(false);
+                let sealed = self.traverse(&source, &|val: &mut String| match val
+                    .strip_prefix(ENCRYPT_PREFIX)
+                {
+                    Some(plain_secret) => {
+                        marked.set(true);
+                        // The sources of these formats are their values.
+                        match f(plain_secret.to_owned())? {
+                            Sealed::Vault(vault) | Sealed::Source(vault) => Ok(Some(vault)),
+                        }
+                    }
+                    None => Ok(None),
+                })?;
+
+                if !marked.get() {
+                    return Ok(None);
+                }
+                Ok(Some(sealed))
+            }
+        }
+    }
+
+    /// Returns every value together with its path.
+    pub fn values(self, source: String) -> Result<Vec<(Vec<Segment>, String)>, Error> {
+        match self {
+            Format::Yaml => Ok(yaml::YamlFile::parse(source)?
+                .scalars()
+                .iter()
+                .filter(|scalar| !scalar.is_key && !scalar.is_empty())
+                .map(|scalar| (scalar.path.clone(), scalar.value.clone()))
+                .collect()),
+            Format::Json => json::values(&source),
+            Format::Toml => toml_file::values(&source),
+            Format::Env => Ok(dotenv::parse(&source)?
+                .into_iter()
+                .map(|entry| (vec![Segment::Key(entry.key)], entry.value))
+                .collect()),
+        }
+    }
+
+    /// Returns every value together with the source text which writes it
+    /// back unchanged. YAML keeps the quoting and tags of single line
+    /// scalars, the other formats only their values.
+    pub fn sources(self, source: String) -> Result<Vec<(String, Sealed)>, Error> {
+        match self {
+            Format::Yaml => {
+                let yml = yaml::YamlFile::parse(source)?;
+                Ok(yml
+                    .scalars()
+                    .iter()
+                    .filter(|scalar| !scalar.is_key && !scalar.is_empty())
+                    .map(|scalar| {
+                        let text = match yml.scalar_source(scalar) {
+                            Some(text) => Sealed::Source(text),
+                            None => Sealed::Vault(scalar.value.clone()),
+                        };
+                        (scalar.value.clone(), text)
+                    })
+                    .collect())
+            }
+            _ => Ok(self
+                .values(source)?
+                .into_iter()
+                .map(|(_, value)| (value.clone(), Sealed::Source(value)))
+                .collect()),
+        }
+    }
+
+    /// How values are marked for encryption in this format.
+    pub fn marker(self) -> String {
+        match self {
+            Format::Yaml => format!("tagged with {}", yaml::ENCRYPT_TAG),
+            Format::Json | Format::Toml | Format::Env => {
+                format!("prefixed with \"{}\"", ENCRYPT_PREFIX)
+            }
+        }
+    }
+
+    /// Flattens the values into variables named after their paths.
+    pub fn variables(
+        self,
+        source: String,
+        separator: &str,
+        uppercase: bool,
+    ) -> Result<Vec<(String, String)>, Error> {
+        match self {
+            Format::Yaml => Ok(yaml::YamlFile::parse(source)?
+                .scalars()
+                .iter()
+                .filter(|scalar| scalar.document == 0 && !scalar.is_key && !scalar.is_empty())
+                .map(|scalar| {
+                    (
+                        dotenv::variable_name(&scalar.path, separator, uppercase),
+                        scalar.value.clone(),
+                    )
+                })
+                .collect()),
+            Format::Env => Ok(dotenv::parse(&source)?
+                .into_iter()
+                .map(|entry| (entry.key, entry.value))
+                .collect()),
+            Format::Json | Format::Toml => Err(format_err!(
+                "Only YAML and dotenv files can be flattened into variables"
+            )),
+        }
+    }
+}
+
+/// Whether the file is named like `.env` or `.env.production`.
+fn is_dotenv(file_path: &str) -> bool {
+    let path = Path::new(file_path);
+    let file_name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    file_name == ".env"
+        || file_name.starts_with(".env.")
+        || path.extension().and_then(|e| e.to_str()) == Some("env")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::unwrap_vault;
+
+    #[test]
+    fn cleans_smudged_files_unchanged() {
+        let sources = [
+            (
+                Format::Yaml,
+                "a: VAULT:123\nb: VAULT:true # c\nc: \"VAULT:multi\\nline\"\n\
+                 d: !!str VAULT:x\ne: 'VAULT:y'\n",
+            ),
+            (
+                Format::Json,
+                "{\"a\": \"VAULT:123\", \"b\": [\"VAULT:x\"]}\n",
+            ),
+            (
+                Format::Toml,
+                "# c\na = \"VAULT:123\"\n[b]\nc = [\"VAULT:x\"]\n",
+            ),
+            (Format::Env, "A=VAULT:123\nB='VAULT:x y'\n"),
+        ];
+        for (format, source) in sources.iter() {
+            let vaults = format.sources(source.to_string()).unwrap();
+            let smudged = format
+                .mark(source.to_string(), &|val: &mut String| unwrap_vault(val))
+                .unwrap();
+            assert_ne!(&smudged, source);
+
+            let restore = |plain_secret: String| {
+                let vault = format!("VAULT:{}", plain_secret);
+                vaults
+                    .iter()
+                    .find(|(value, _)| value == &vault)
+                    .map(|(_, text)| text.clone())
+                    .ok_or_else(|| format_err!("No vault for {}", plain_secret))
+            };
+            assert_eq!(&format.seal(smudged, &restore).unwrap().unwrap(), source);
+        }
+    }
+
+    #[test]
+    fn reports_path_of_failing_value() {
+        let sources = [
+            (Format::Yaml, "db:\n  replicas:\n    - ok\n    - bad\n"),
+            (Format::Json, r#"{"db": {"replicas": ["ok", "bad"]}}"#),
+            (Format::Toml, "[db]\nreplicas = [\"ok\", \"bad\"]\n"),
+        ];
+        let fail = |val: &mut String| match val.as_str() {
+            "bad" => Err(format_err!("broken")),
+            _ => Ok(None),
+        };
+        for (format, source) in sources.iter() {
+            assert_eq!(
+                format.traverse(source, &fail).unwrap_err().to_string(),
+                "Failed to replace value at db.replicas[1]"
+            );
+        }
+        assert_eq!(
+            Format::Env
+                .traverse("A=ok\nB=bad\n", &fail)
+                .unwrap_err()
+                .to_string(),
+            "Failed to replace value at B"
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use failure::{Error, ResultExt};
+use std::path::Path;
+
+use crate::lint::wildcard_match;
+use crate::yaml::{display_path, YamlFile};
+
+/// Config file of a repository, next to its `.gitattributes`.
+pub const REPO_CONFIG_FILE: &str = ".culper.toml";
+
+/// Marks pre-commit hooks written by culper.
+const HOOK_MARKER: &str = "# Installed by culper hook install.";
+
+/// Settings of a repository which uses culper.
+#[derive(Deserialize, Default, Debug)]
+pub struct RepoConfig {
+    /// Paths of values which have to be encrypted, e.g. `db.password` or
+    /// `*.token`, where `*` matches anything.
+    #[serde(default)]
+    pub secrets: Vec<String>,
+}
+
+impl RepoConfig {
+    pub fn parse(source: &str) -> Result<RepoConfig, Error> {
+        Ok(toml::from_str(source).context(format!("Could not parse {}", REPO_CONFIG_FILE))?)
+    }
+}
+
+/// Returns the pre-commit hook which runs `command`.
+pub fn pre_commit_hook(command: &str) -> String {
+    format!(
+        "#!/bin/sh\n{}\n# Rejects commits of plaintext secrets.\nexec {} hook pre-commit\n",
+        HOOK_MARKER, command
+    )
+}
+
+/// Whether an existing hook was written by culper and can be replaced.
+pub fn is_own_hook(hook: &str) -> bool {
+    hook.lines().any(|line| line == HOOK_MARKER)
+}
+
+/// Whether the hook checks a file, which it only does for YAML files.
+pub fn is_yaml(file_path: &str) -> bool {
+    match Path::new(file_path).extension().and_then(|e| e.to_str()) {
+        Some(extension) => {
+            extension.eq_ignore_ascii_case("yml") || extension.eq_ignore_ascii_case("yaml")
+        }
+        None => false,
+    }
+}
+
+/// Returns the lines and paths of values which have to be encrypted but
+/// are no vaults.
+pub fn plaintext_secrets<F>(
+    yml: &YamlFile,
+    config: &RepoConfig,
+    is_vault: &F,
+) -> Vec<(usize, String)>
+where
+    F: Fn(&str) -> bool,
+{
+    yml.scalars()
+        .iter()
+        .filter(|scalar| !scalar.is_key && !scalar.is_empty() && !is_vault(&scalar.value))
+        .map(|scalar| (yml.line(scalar), display_path(&scalar.path)))
+        .filter(|(_, path)| {
+            config
+                .secrets
+                .iter()
+                .any(|pattern| wildcard_match(pattern, path))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_plaintext_secrets() {
+        let config = RepoConfig::parse("secrets = [\"db.password\", \"*.token\"]\n").unwrap();
+        let yml = YamlFile::parse(
+            "db:\n  user: app\n  password: hunter2\napi:\n  token: VAULT:x\nslack:\n  token: xoxb\n"
+                .to_owned(),
+        )
+        .unwrap();
+        let is_vault = |value: &str| value.starts_with("VAULT:");
+
+        assert_eq!(
+            plaintext_secrets(&yml, &config, &is_vault),
+            vec![(3, "db.password".to_owned()), (7, "slack.token".to_owned())]
+        );
+        assert!(plaintext_secrets(&yml, &RepoConfig::default(), &is_vault).is_empty());
+    }
+
+    #[test]
+    fn checks_only_yaml_files() {
+        assert!(is_yaml("config/secrets.yml"));
+        assert!(is_yaml("values.YAML"));
+        assert!(!is_yaml("src/culper.rs"));
+        assert!(!is_yaml("README.md"));
+        assert!(!is_yaml("Makefile"));
+        assert!(!is_yaml("config.json"));
+    }
+
+    #[test]
+    fn recognizes_own_hooks() {
+        assert!(is_own_hook(&pre_commit_hook("culper")));
+        assert!(!is_own_hook("#!/bin/sh\nmake lint\n"));
+    }
+}
@@ -0,0 +1,413 @@
+use failure::{Error, ResultExt};
+use serde_json::{Map, Value};
+use std::ops::Range;
+
+use crate::yaml::{display_path, Segment};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Object,
+    Array,
+    String,
+    Null,
+    Other,
+}
+
+/// A value of a JSON document together with the location of its source
+/// text, so it can be replaced without touching the rest of the file.
+struct Node {
+    path: Vec<Segment>,
+    kind: Kind,
+    /// The decoded text of strings.
+    value: String,
+    span: Range<usize>,
+    /// Where the last entry of an object ends.
+    last_entry_end: Option<usize>,
+    /// Whitespace in front of the first entry of an object on its own line.
+    entry_indent: Option<String>,
+}
+
+/// A parsed JSON document which keeps its original source text.
+///
+/// Rewriting only touches the source text of replaced strings, so number
+/// precision, key order and formatting of everything else survive.
+struct JsonFile {
+    source: String,
+    nodes: Vec<Node>,
+}
+
+impl JsonFile {
+    fn parse(source: &str) -> Result<JsonFile, Error> {
+        // serde_json reports errors with their location, the scanner below
+        // only has to deal with valid documents.
+        serde_json::from_str::<Value>(source).context("Could not parse JSON")?;
+
+        let mut scanner = Scanner {
+            source,
+            position: 0,
+            nodes: vec![],
+        };
+        scanner.value(&mut vec![])?;
+        Ok(JsonFile {
+            source: source.to_owned(),
+            nodes: scanner.nodes,
+        })
+    }
+
+    fn find(&self, path: &[Segment]) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.path == path)
+    }
+
+    fn strings(&self) -> impl Iterator<Item = &Node> {
+        self.nodes.iter().filter(|node| node.kind == Kind::String)
+    }
+
+    /// Returns the source text with the given byte ranges replaced.
+    fn splice(&self, mut edits: Vec<(Range<usize>, String)>) -> String {
+        edits.sort_by_key(|(range, _)| range.start);
+
+        let mut output = String::with_capacity(self.source.len());
+        let mut position = 0;
+        for (range, text) in edits {
+            output.push_str(&self.source[position..range.start]);
+            output.push_str(&text);
+            position = range.end;
+        }
+        output.push_str(&self.source[position..]);
+        output
+    }
+
+    /// Whitespace at the start of the line the given offset is on.
+    fn line_indent(&self, offset: usize) -> &str {
+        let line_start = self.source[..offset]
+            .rfind('\n')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let line = &self.source[line_start..];
+        &line[..line.len() - line.trim_start().len()]
+    }
+}
+
+struct Scanner<'a> {
+    source: &'a str,
+    position: usize,
+    nodes: Vec<Node>,
+}
+
+impl<'a> Scanner<'a> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.source[self.position..];
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.source.as_bytes().get(self.position).cloned()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), Error> {
+        self.skip_whitespace();
+        if self.peek() != Some(byte) {
+            return Err(format_err!(
+                "Expected {} at byte {} of JSON",
+                byte as char,
+                self.position
+            ));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn push(&mut self, path: &[Segment], kind: Kind, start: usize) -> usize {
+        self.nodes.push(Node {
+            path: path.to_vec(),
+            kind,
+            value: String::new(),
+            span: start..start,
+            last_entry_end: None,
+            entry_indent: None,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn value(&mut self, path: &mut Vec<Segment>) -> Result<(), Error> {
+        self.skip_whitespace();
+        let start = self.position;
+        match self.peek() {
+            Some(b'{') => {
+                let index = self.push(path, Kind::Object, start);
+                self.position += 1;
+                self.skip_whitespace();
+                if self.peek() != Some(b'}') {
+                    if self.source[start..self.position].contains('\n') {
+                        let indent = &self.source[..self.position];
+                        let line_start = indent.rfind('\n').map(|i| i + 1).unwrap_or(0);
+                        self.nodes[index].entry_indent = Some(indent[line_start..].to_owned());
+                    }
+                    loop {
+                        let key = self.string()?.1;
+                        self.expect(b':')?;
+                        path.push(Segment::Key(key));
+                        self.value(path)?;
+                        path.pop();
+                        self.nodes[index].last_entry_end = Some(self.position);
+
+                        self.skip_whitespace();
+                        if self.peek() != Some(b',') {
+                            break;
+                        }
+                        self.position += 1;
+                    }
+                }
+                self.expect(b'}')?;
+                self.nodes[index].span = start..self.position;
+            }
+            Some(b'[') => {
+                let index = self.push(path, Kind::Array, start);
+                self.position += 1;
+                self.skip_whitespace();
+                if self.peek() != Some(b']') {
+                    let mut item = 0;
+                    loop {
+                        path.push(Segment::Index(item));
+                        self.value(path)?;
+                        path.pop();
+                        item += 1;
+
+                        self.skip_whitespace();
+                        if self.peek() != Some(b',') {
+                            break;
+                        }
+                        self.position += 1;
+                    }
+                }
+                self.expect(b']')?;
+                self.nodes[index].span = start..self.position;
+            }
+            Some(b'"') => {
+                let (span, value) = self.string()?;
+                let index = self.push(path, Kind::String, start);
+                self.nodes[index].span = span;
+                self.nodes[index].value = value;
+            }
+            _ => {
+                // Numbers, booleans and null end at the next delimiter.
+                let length = self.source[start..]
+                    .find(|c: char| c.is_whitespace() || ",]}".contains(c))
+                    .unwrap_or_else(|| self.source.len() - start);
+                self.position += length;
+                let kind = match &self.source[start..self.position] {
+                    "null" => Kind::Null,
+                    _ => Kind::Other,
+                };
+                let index = self.push(path, kind, start);
+                self.nodes[index].span = start..self.position;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a string literal and returns its span and decoded text.
+    fn string(&mut self) -> Result<(Range<usize>, String), Error> {
+        self.skip_whitespace();
+        let start = self.position;
+        let bytes = self.source.as_bytes();
+        let mut i = start + 1;
+        while i < bytes.len() && bytes[i] != b'"' {
+            i += if bytes[i] == b'\\' { 2 } else { 1 };
+        }
+        self.position = (i + 1).min(bytes.len());
+        let value = serde_json::from_str(&self.source[start..self.position])?;
+        Ok((start..self.position, value))
+    }
+}
+
+pub fn traverse_json<F>(source: &str, f: &F) -> Result<String, Error>
+where
+    F: Fn(&mut String) -> Result<Option<String>, Error>,
+{
+    let json = JsonFile::parse(source)?;
+
+    let mut edits = vec![];
+    for node in json.strings() {
+        let new_value = f(&mut node.value.clone())
+            .with_context(|_| format!("Failed to replace value at {}", display_path(&node.path)))?;
+        if let Some(new_value) = new_value {
+            edits.push((node.span.clone(), serde_json::to_string(&new_value)?));
+        }
+    }
+    Ok(json.splice(edits))
+}
+
+/// Returns every string together with its path.
+pub fn values(source: &str) -> Result<Vec<(Vec<Segment>, String)>, Error> {
+    Ok(JsonFile::parse(source)?
+        .strings()
+        .map(|node| (node.path.clone(), node.value.clone()))
+        .collect())
+}
+
+/// Sets the string at `path`, creating missing objects on the way.
+pub fn set(source: &str, path: &[Segment], value: &str) -> Result<String, Error> {
+    if source.trim().is_empty() {
+        return Ok(format!("{}\n", format_entries(path, value, "")?));
+    }
+    let json = JsonFile::parse(source)?;
+
+    if let Some(node) = json.find(path) {
+        return match node.kind {
+            Kind::String | Kind::Null => {
+                Ok(json.splice(vec![(node.span.clone(), serde_json::to_string(value)?)]))
+            }
+            _ => Err(format_err!("{} is not a scalar", display_path(path))),
+        };
+    }
+
+    for depth in (0..path.len()).rev() {
+        let (parent, missing) = path.split_at(depth);
+        let node = match json.find(parent) {
+            Some(node) => node,
+            None => continue,
+        };
+
+        return match (&missing[0], node.kind) {
+            (Segment::Key(_), Kind::Object) => {
+                let indent = json.line_indent(node.span.start);
+                let edit = match (node.last_entry_end, &node.entry_indent) {
+                    (Some(end), Some(entry_indent)) => (
+                        end..end,
+                        format!(
+                            ",\n{}{}",
+                            entry_indent,
+                            format_entry(missing, value, entry_indent)?
+                        ),
+                    ),
+                    (Some(end), None) => (
+                        end..end,
+                        format!(", {}", format_entry(missing, value, indent)?),
+                    ),
+                    (None, _) => (node.span.clone(), format_entries(missing, value, indent)?),
+                };
+                Ok(json.splice(vec![edit]))
+            }
+            (Segment::Key(_), Kind::Null) => {
+                let indent = json.line_indent(node.span.start);
+                Ok(json.splice(vec![(
+                    node.span.clone(),
+                    format_entries(missing, value, indent)?,
+                )]))
+            }
+            (Segment::Index(_), Kind::Array) => {
+                Err(format_err!("No value at {}", display_path(&path[..=depth])))
+            }
+            (Segment::Key(_), _) => Err(format_err!("{} is not an object", display_path(parent))),
+            (Segment::Index(_), _) => Err(format_err!("{} is not an array", display_path(parent))),
+        };
+    }
+    Err(format_err!("No value at {}", display_path(path)))
+}
+
+/// Formats nested objects leading to `value`, continuing lines with
+/// `indent`.
+fn format_entries(path: &[Segment], value: &str, indent: &str) -> Result<String, Error> {
+    let mut json = Value::String(value.to_owned());
+    for segment in path.iter().rev() {
+        match segment {
+            Segment::Key(key) => {
+                let mut map = Map::new();
+                map.insert(key.clone(), json);
+                json = Value::Object(map);
+            }
+            Segment::Index(_) => {
+                return Err(format_err!(
+                    "Cannot create array item at {}",
+                    display_path(path)
+                ))
+            }
+        }
+    }
+    let formatted = serde_json::to_string_pretty(&json)?;
+    Ok(formatted.replace('\n', &format!("\n{}", indent)))
+}
+
+/// Formats a single `"key": value` entry of an object.
+fn format_entry(path: &[Segment], value: &str, indent: &str) -> Result<String, Error> {
+    match &path[0] {
+        Segment::Key(key) => Ok(format!(
+            "{}: {}",
+            serde_json::to_string(key)?,
+            format_entries(&path[1..], value, indent)?
+        )),
+        Segment::Index(_) => Err(format_err!(
+            "Cannot create array item at {}",
+            display_path(path)
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::unwrap_vault;
+
+    #[test]
+    fn rewrites_only_vaults_keeping_formatting() {
+        let source = "{\"z\": \"VAULT:a\",\n \"n\": [12345678901234567890123, 1.50],\n\
+                      \"a\": [1, \"VAULT:b\\\"\", {\"m\": null}], \"VAULT:k\": \"c\"}";
+        let expected = "{\"z\": \"a\",\n \"n\": [12345678901234567890123, 1.50],\n\
+                        \"a\": [1, \"b\\\"\", {\"m\": null}], \"VAULT:k\": \"c\"}";
+        assert_eq!(
+            traverse_json(source, &|val: &mut String| unwrap_vault(val)).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn sets_values_at_paths() {
+        let path = [
+            Segment::Key("db".to_owned()),
+            Segment::Key("password".to_owned()),
+        ];
+        assert_eq!(
+            set("", &path, "secret").unwrap(),
+            "{\n  \"db\": {\n    \"password\": \"secret\"\n  }\n}\n"
+        );
+        assert_eq!(
+            set(r#"{"b": 1.50, "db": null}"#, &path, "secret").unwrap(),
+            "{\"b\": 1.50, \"db\": {\n  \"password\": \"secret\"\n}}"
+        );
+        assert_eq!(
+            set("{\n  \"b\": 1,\n  \"db\": {\n    \"user\": \"x\"\n  }\n}\n", &path, "secret")
+                .unwrap(),
+            "{\n  \"b\": 1,\n  \"db\": {\n    \"user\": \"x\",\n    \"password\": \"secret\"\n  }\n}\n"
+        );
+        assert_eq!(
+            set(r#"{"db": {}}"#, &path, "secret").unwrap(),
+            "{\"db\": {\n  \"password\": \"secret\"\n}}"
+        );
+        assert_eq!(
+            set(r#"{"db": 1}"#, &path, "secret")
+                .unwrap_err()
+                .to_string(),
+            "db is not an object"
+        );
+        assert_eq!(
+            set(r#"{"db": {"password": []}}"#, &path, "secret")
+                .unwrap_err()
+                .to_string(),
+            "db.password is not a scalar"
+        );
+    }
+
+    #[test]
+    fn lists_values_with_paths() {
+        let values = values(r#"{"db": {"replicas": ["a", 1]}, "b": "c"}"#).unwrap();
+        let values: Vec<_> = values
+            .iter()
+            .map(|(path, value)| (display_path(path), value.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            vec![("db.replicas[0]".to_owned(), "a"), ("b".to_owned(), "c")]
+        );
+    }
+}
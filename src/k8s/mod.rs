@@ -0,0 +1,168 @@
+use base64::encode;
+use failure::{Error, ResultExt};
+
+use crate::yaml::{self, display_path, Segment, YamlFile};
+
+/// Builds a `kind: Secret` manifest holding the given entries, base64
+/// encoded under `data` or as they are under `stringData`.
+pub fn secret_manifest(
+    name: &str,
+    namespace: Option<&str>,
+    entries: &[(String, String)],
+    string_data: bool,
+) -> Result<String, Error> {
+    let mut manifest = String::from("apiVersion: v1\nkind: Secret\nmetadata:\n");
+    manifest.push_str(&format!("  name: {}\n", yaml::format_inline(name)));
+    if let Some(namespace) = namespace {
+        manifest.push_str(&format!(
+            "  namespace: {}\n",
+            yaml::format_inline(namespace)
+        ));
+    }
+    manifest.push_str("type: Opaque\n");
+
+    let field = if string_data { "stringData" } else { "data" };
+    if entries.is_empty() {
+        manifest.push_str(&format!("{}: {{}}\n", field));
+        return Ok(manifest);
+    }
+
+    manifest.push_str(&format!("{}:\n", field));
+    for (key, value) in entries {
+        if !is_valid_key(key) {
+            return Err(format_err!("{} is not a valid Secret key", key));
+        }
+        let value = if string_data {
+            yaml::format_inline(value)
+        } else {
+            encode(value)
+        };
+        manifest.push_str(&format!("  {}: {}\n", yaml::format_inline(key), value));
+    }
+    Ok(manifest)
+}
+
+/// Decrypts the vaults under `data` and `stringData` of every Secret in a
+/// stream of manifests, base64 encoding the values under `data`.
+///
+/// Returns `None` if the stream holds no Secret.
+pub fn decrypt_secrets<F>(yml: &YamlFile, f: &F) -> Result<Option<String>, Error>
+where
+    F: Fn(&mut String) -> Result<Option<String>, Error>,
+{
+    if secret_documents(yml).is_empty() {
+        return Ok(None);
+    }
+    decrypt(yml, f, true).map(Some)
+}
+
+/// Decrypts every vault in a stream of manifests, base64 encoding the
+/// values under `data` of Secrets.
+pub fn decrypt_manifests<F>(yml: &YamlFile, f: &F) -> Result<String, Error>
+where
+    F: Fn(&mut String) -> Result<Option<String>, Error>,
+{
+    decrypt(yml, f, false)
+}
+
+fn decrypt<F>(yml: &YamlFile, f: &F, only_secrets: bool) -> Result<String, Error>
+where
+    F: Fn(&mut String) -> Result<Option<String>, Error>,
+{
+    let secrets = secret_documents(yml);
+
+    let mut replacements = vec![];
+    for (index, scalar) in yml.scalars().iter().enumerate() {
+        if scalar.is_key || scalar.is_empty() {
+            continue;
+        }
+        let is_secret = secrets.contains(&scalar.document);
+        let encoded = match scalar.path.as_slice() {
+            [Segment::Key(field), Segment::Key(_)] if is_secret && field == "data" => true,
+            [Segment::Key(field), Segment::Key(_)] if is_secret && field == "stringData" => false,
+            _ if only_secrets => continue,
+            _ => false,
+        };
+
+        let new_value = f(&mut scalar.value.to_owned()).with_context(|_| {
+            format!("Failed to replace value at {}", display_path(&scalar.path))
+        })?;
+        if let Some(new_value) = new_value {
+            let new_value = if encoded {
+                encode(&new_value)
+            } else {
+                new_value
+            };
+            replacements.push((index, new_value));
+        }
+    }
+    Ok(yml.rewrite(&replacements))
+}
+
+/// Returns the documents of the stream which are Secrets.
+fn secret_documents(yml: &YamlFile) -> Vec<usize> {
+    let kind = [Segment::Key("kind".to_owned())];
+    yml.scalars()
+        .iter()
+        .filter(|scalar| !scalar.is_key && scalar.path == kind && scalar.value == "Secret")
+        .map(|scalar| scalar.document)
+        .collect()
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::unwrap_vault;
+
+    #[test]
+    fn builds_secret_manifests() {
+        let entries = vec![("DB_PASSWORD".to_owned(), "secret".to_owned())];
+        assert_eq!(
+            secret_manifest("app-secrets", Some("prod"), &entries, false).unwrap(),
+            "apiVersion: v1\nkind: Secret\nmetadata:\n  name: app-secrets\n  namespace: prod\n\
+             type: Opaque\ndata:\n  DB_PASSWORD: c2VjcmV0\n"
+        );
+        assert_eq!(
+            secret_manifest("app-secrets", None, &entries, true).unwrap(),
+            "apiVersion: v1\nkind: Secret\nmetadata:\n  name: app-secrets\n\
+             type: Opaque\nstringData:\n  DB_PASSWORD: secret\n"
+        );
+        let entries = vec![("db password".to_owned(), "secret".to_owned())];
+        assert!(secret_manifest("app-secrets", None, &entries, false).is_err());
+    }
+
+    #[test]
+    fn decrypts_secret_data() {
+        let source = "kind: ConfigMap\ndata:\n  a: VAULT:x\n---\nkind: Secret\n\
+                      metadata:\n  name: VAULT:app\ndata:\n  a: VAULT:secret # b64\n\
+                      stringData:\n  b: VAULT:plain\n";
+        let yml = YamlFile::parse(source.to_owned()).unwrap();
+        assert_eq!(
+            decrypt_secrets(&yml, &|val: &mut String| unwrap_vault(val))
+                .unwrap()
+                .unwrap(),
+            "kind: ConfigMap\ndata:\n  a: VAULT:x\n---\nkind: Secret\n\
+             metadata:\n  name: VAULT:app\ndata:\n  a: c2VjcmV0 # b64\n\
+             stringData:\n  b: plain\n"
+        );
+
+        assert_eq!(
+            decrypt_manifests(&yml, &|val: &mut String| unwrap_vault(val)).unwrap(),
+            "kind: ConfigMap\ndata:\n  a: x\n---\nkind: Secret\n\
+             metadata:\n  name: app\ndata:\n  a: c2VjcmV0 # b64\n\
+             stringData:\n  b: plain\n"
+        );
+
+        let yml = YamlFile::parse("kind: ConfigMap\n".to_owned()).unwrap();
+        assert!(decrypt_secrets(&yml, &|val: &mut String| unwrap_vault(val))
+            .unwrap()
+            .is_none());
+    }
+}
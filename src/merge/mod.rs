@@ -0,0 +1,271 @@
+use failure::Error;
+use std::collections::{HashMap, HashSet};
+
+use crate::yaml::{display_path, Segment, YamlFile};
+
+/// A scalar value together with its plain secret, so re-encrypted values
+/// compare equal.
+struct Leaf {
+    value: String,
+    plain: String,
+    is_vault: bool,
+    /// Source text including style and tag, so a copied `9090` stays a
+    /// number.
+    source: Option<String>,
+}
+
+/// What an updated value is written as.
+enum Content {
+    Value(String),
+    Source(String),
+}
+
+/// Document and path of a value.
+type Location = (usize, Vec<Segment>);
+
+type Leaves = HashMap<Location, Leaf>;
+
+/// Merges the values of `ours` and `theirs` which changed compared to
+/// `base`, key by key. `plain` returns the plain secret of vaults.
+///
+/// The result is based on `ours`, vaults taken from `theirs` are sealed
+/// again with `seal`. Fails with the paths of all conflicting values.
+pub fn merge<F, S>(
+    base: &YamlFile,
+    ours: &YamlFile,
+    theirs: &YamlFile,
+    plain: &F,
+    seal: &S,
+) -> Result<String, Error>
+where
+    F: Fn(&str) -> Result<Option<String>, Error>,
+    S: Fn(String) -> Result<String, Error>,
+{
+    let (base_leaves, base_order) = leaves(base, plain)?;
+    let (our_leaves, mut order) = leaves(ours, plain)?;
+    let (their_leaves, their_order) = leaves(theirs, plain)?;
+    order.extend(their_order);
+    order.extend(base_order);
+
+    let same = |a: Option<&Leaf>, b: Option<&Leaf>| match (a, b) {
+        (Some(a), Some(b)) => a.plain == b.plain,
+        (None, None) => true,
+        _ => false,
+    };
+
+    let mut conflicts = vec![];
+    let mut removals = vec![];
+    let mut updates = vec![];
+    let mut seen = HashSet::new();
+    for key in order {
+        if !seen.insert(key.clone()) {
+            continue;
+        }
+        let (o, a, b) = (
+            base_leaves.get(&key),
+            our_leaves.get(&key),
+            their_leaves.get(&key),
+        );
+        if same(a, b) || same(o, b) {
+            // Both sides agree or only ours changed.
+        } else if same(o, a) {
+            match b {
+                Some(leaf) => updates.push((key.clone(), leaf)),
+                None => removals.push(key.clone()),
+            }
+        } else {
+            conflicts.push(describe(&key, None));
+        }
+    }
+
+    // Removals go first, so a value which turned into a mapping or the
+    // other way round can be added afterwards.
+    let mut merged = ours.rewrite(&[]);
+    for key in &removals {
+        match remove(&merged, key) {
+            Ok(removed) => merged = removed,
+            Err(e) => conflicts.push(describe(key, Some(e))),
+        }
+    }
+    // Mappings whose entries were all removed are left behind as empty
+    // values, they go as well unless theirs still has them.
+    for (document, path) in &removals {
+        merged = prune(merged, *document, path, theirs)?;
+    }
+    for (key, leaf) in updates {
+        // Nothing needs to be sealed once the merge failed.
+        let content = if leaf.is_vault && conflicts.is_empty() {
+            Content::Value(seal(leaf.plain.clone())?)
+        } else if leaf.is_vault {
+            Content::Value(leaf.value.clone())
+        } else {
+            match &leaf.source {
+                Some(source) => Content::Source(source.clone()),
+                None => Content::Value(leaf.value.clone()),
+            }
+        };
+        match update(&merged, &key, &content) {
+            Ok(updated) => merged = updated,
+            Err(e) => conflicts.push(describe(&key, Some(e))),
+        }
+    }
+
+    if !conflicts.is_empty() {
+        return Err(format_err!(
+            "Conflicting changes at:\n    {}",
+            conflicts.join("\n    ")
+        ));
+    }
+    Ok(merged)
+}
+
+/// Returns the scalar values by document and path, and their order.
+fn leaves<F>(yml: &YamlFile, plain: &F) -> Result<(Leaves, Vec<Location>), Error>
+where
+    F: Fn(&str) -> Result<Option<String>, Error>,
+{
+    let mut leaves = HashMap::new();
+    let mut order = vec![];
+    for scalar in yml.scalars().iter().filter(|scalar| !scalar.is_key) {
+        let key = (scalar.document, scalar.path.clone());
+        let leaf = match plain(&scalar.value)? {
+            Some(plain_secret) => Leaf {
+                value: scalar.value.clone(),
+                plain: plain_secret,
+                is_vault: true,
+                source: None,
+            },
+            None => Leaf {
+                value: scalar.value.clone(),
+                plain: scalar.value.clone(),
+                is_vault: false,
+                source: yml.scalar_source(scalar),
+            },
+        };
+        order.push(key.clone());
+        leaves.insert(key, leaf);
+    }
+    Ok((leaves, order))
+}
+
+fn remove(source: &str, (document, path): &Location) -> Result<String, Error> {
+    if *document != 0 {
+        return Err(format_err!(
+            "only the first document can be merged structurally"
+        ));
+    }
+    YamlFile::parse(source.to_owned())?.remove(path)
+}
+
+/// Removes the parents of a removed value which are empty now, as long as
+/// `theirs` does not have them.
+fn prune(
+    mut source: String,
+    document: usize,
+    path: &[Segment],
+    theirs: &YamlFile,
+) -> Result<String, Error> {
+    if document != 0 {
+        return Ok(source);
+    }
+    for depth in (1..path.len()).rev() {
+        let parent = &path[..depth];
+        if theirs.contains(parent) || !matches!(parent.last(), Some(Segment::Key(_))) {
+            break;
+        }
+        let yml = YamlFile::parse(source.clone())?;
+        match yml.get(parent) {
+            Ok(scalar) if scalar.is_empty() => source = yml.remove(parent)?,
+            _ => break,
+        }
+    }
+    Ok(source)
+}
+
+fn update(source: &str, (document, path): &Location, content: &Content) -> Result<String, Error> {
+    let yml = YamlFile::parse(source.to_owned())?;
+    let index = yml
+        .scalars()
+        .iter()
+        .position(|scalar| scalar.document == *document && !scalar.is_key && &scalar.path == path);
+
+    match (index, content) {
+        (Some(index), Content::Value(value)) => Ok(yml.rewrite(&[(index, value.to_owned())])),
+        (Some(index), Content::Source(text)) => Ok(yml.rewrite_source(&[(index, text.to_owned())])),
+        (None, Content::Value(value)) if *document == 0 => yml.set(path, value),
+        (None, Content::Source(text)) if *document == 0 => yml.set_source(path, text),
+        (None, _) => Err(format_err!(
+            "only the first document can be merged structurally"
+        )),
+    }
+}
+
+fn describe((document, path): &Location, reason: Option<Error>) -> String {
+    let mut description = display_path(path);
+    if *document > 0 {
+        description = format!("{} of document {}", description, document + 1);
+    }
+    match reason {
+        Some(reason) => format!("{} ({})", description, reason),
+        None => description,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::unwrap_vault;
+
+    fn merge_sources(base: &str, ours: &str, theirs: &str) -> Result<String, Error> {
+        let parse = |source: &str| YamlFile::parse(source.to_owned()).unwrap();
+        let seal = |plain: String| Ok(format!("VAULT:{}", plain));
+        merge(
+            &parse(base),
+            &parse(ours),
+            &parse(theirs),
+            &unwrap_vault,
+            &seal,
+        )
+    }
+
+    #[test]
+    fn merges_changes_of_different_keys() {
+        let base = "a: VAULT:1 # first\nb: VAULT:2\nc: VAULT:3\n";
+        let ours = "a: VAULT:1 # first\nb: VAULT:20\nc: VAULT:3\n";
+        let theirs = "a: VAULT:10\nb: VAULT:2\nd:\n  e: VAULT:4\n";
+        assert_eq!(
+            merge_sources(base, ours, theirs).unwrap(),
+            "a: VAULT:10 # first\nb: VAULT:20\nd:\n  e: VAULT:4\n"
+        );
+    }
+
+    #[test]
+    fn reports_conflicting_keys() {
+        let base = "db:\n  password: VAULT:1\n  user: VAULT:u\nb: VAULT:2\n";
+        let ours = "db:\n  password: VAULT:10\n  user: VAULT:u\nb: VAULT:20\n";
+        let theirs = "db:\n  password: VAULT:11\nb: VAULT:2\n";
+        assert_eq!(
+            merge_sources(base, ours, theirs).unwrap_err().to_string(),
+            "Conflicting changes at:\n    db.password"
+        );
+    }
+
+    #[test]
+    fn keeps_types_of_their_values() {
+        let base = "port: 8080\nenabled: true\nname: a\n";
+        let ours = "port: 8080\nenabled: true\nname: b\n";
+        let theirs = "port: 9090\nenabled: false\nname: a\nretries: !!int 3\n";
+        assert_eq!(
+            merge_sources(base, ours, theirs).unwrap(),
+            "port: 9090\nenabled: false\nname: b\nretries: !!int 3\n"
+        );
+    }
+
+    #[test]
+    fn removes_mappings_deleted_by_them() {
+        let base = "db:\n  a: VAULT:1\n  b: VAULT:2\nc: 1\n";
+        let ours = "db:\n  a: VAULT:1\n  b: VAULT:2\nc: 2\n";
+        let theirs = "c: 1\n";
+        assert_eq!(merge_sources(base, ours, theirs).unwrap(), "c: 2\n");
+    }
+}
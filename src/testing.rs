@@ -0,0 +1,8 @@
+//! Helpers shared by the tests of the file formats.
+
+use failure::Error;
+
+/// Unwraps the stand-in vaults of the tests, `VAULT:<plain secret>`.
+pub fn unwrap_vault(val: &str) -> Result<Option<String>, Error> {
+    Ok(val.strip_prefix("VAULT:").map(str::to_owned))
+}
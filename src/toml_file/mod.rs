@@ -0,0 +1,142 @@
+use failure::{Error, ResultExt};
+use std::cell::RefCell;
+use toml_edit::{DocumentMut, Formatted, Item, Value};
+
+use crate::yaml::{display_path, Segment};
+
+/// Replaces strings through `toml_edit`, which keeps comments, key order
+/// and formatting of everything else.
+pub fn traverse_toml<F>(source: &str, f: &F) -> Result<String, Error>
+where
+    F: Fn(&mut String) -> Result<Option<String>, Error>,
+{
+    let mut document = source
+        .parse::<DocumentMut>()
+        .context("Could not parse TOML")?;
+    walk_item(
+        document.as_item_mut(),
+        &mut vec![],
+        &|_: &[Segment], val: &mut String| f(val),
+    )?;
+    Ok(document.to_string())
+}
+
+/// Returns every string together with its path.
+pub fn values(source: &str) -> Result<Vec<(Vec<Segment>, String)>, Error> {
+    let mut document = source
+        .parse::<DocumentMut>()
+        .context("Could not parse TOML")?;
+    let values = RefCell::new(vec![]);
+    walk_item(
+        document.as_item_mut(),
+        &mut vec![],
+        &|path: &[Segment], val: &mut String| {
+            values.borrow_mut().push((path.to_vec(), val.clone()));
+            Ok(None)
+        },
+    )?;
+    Ok(values.into_inner())
+}
+
+fn walk_item<F>(item: &mut Item, path: &mut Vec<Segment>, f: &F) -> Result<(), Error>
+where
+    F: Fn(&[Segment], &mut String) -> Result<Option<String>, Error>,
+{
+    match item {
+        Item::Value(value) => walk_value(value, path, f)?,
+        Item::Table(table) => {
+            for (key, item) in table.iter_mut() {
+                path.push(Segment::Key(key.get().to_owned()));
+                walk_item(item, path, f)?;
+                path.pop();
+            }
+        }
+        Item::ArrayOfTables(tables) => {
+            for (index, table) in tables.iter_mut().enumerate() {
+                path.push(Segment::Index(index));
+                for (key, item) in table.iter_mut() {
+                    path.push(Segment::Key(key.get().to_owned()));
+                    walk_item(item, path, f)?;
+                    path.pop();
+                }
+                path.pop();
+            }
+        }
+        Item::None => (),
+    }
+    Ok(())
+}
+
+fn walk_value<F>(value: &mut Value, path: &mut Vec<Segment>, f: &F) -> Result<(), Error>
+where
+    F: Fn(&[Segment], &mut String) -> Result<Option<String>, Error>,
+{
+    match value {
+        Value::String(formatted) => {
+            let new_value = f(path, &mut formatted.value().clone())
+                .with_context(|_| format!("Failed to replace value at {}", display_path(path)))?;
+            if let Some(new_value) = new_value {
+                let decor = formatted.decor().clone();
+                *formatted = Formatted::new(new_value);
+                *formatted.decor_mut() = decor;
+            }
+        }
+        Value::Array(values) => {
+            for (index, value) in values.iter_mut().enumerate() {
+                path.push(Segment::Index(index));
+                walk_value(value, path, f)?;
+                path.pop();
+            }
+        }
+        Value::InlineTable(table) => {
+            for (key, value) in table.iter_mut() {
+                path.push(Segment::Key(key.get().to_owned()));
+                walk_value(value, path, f)?;
+                path.pop();
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::unwrap_vault;
+
+    #[test]
+    fn rewrites_vaults_in_tables_arrays_and_inline_tables() {
+        let source = r#"
+name = "VAULT:app"
+
+[db]
+password = "VAULT:secret"
+port = 5432
+replicas = [{ host = "a", password = "VAULT:first" }]
+
+[[users]]
+token = "VAULT:t\"ok"
+"#;
+        let decrypted = traverse_toml(source, &|val: &mut String| unwrap_vault(val)).unwrap();
+        let document: toml::Value = toml::from_str(&decrypted).unwrap();
+
+        assert_eq!(document["name"].as_str(), Some("app"));
+        assert_eq!(document["db"]["password"].as_str(), Some("secret"));
+        assert_eq!(document["db"]["port"].as_integer(), Some(5432));
+        assert_eq!(
+            document["db"]["replicas"][0]["password"].as_str(),
+            Some("first")
+        );
+        assert_eq!(document["users"][0]["token"].as_str(), Some("t\"ok"));
+    }
+
+    #[test]
+    fn keeps_comments_and_key_order() {
+        let source = "# settings\nzeta = \"VAULT:1\" # first\nalpha = 2\n\n[db]\n# login\nuser = 'VAULT:x'\n";
+        assert_eq!(
+            traverse_toml(source, &|val: &mut String| unwrap_vault(val)).unwrap(),
+            "# settings\nzeta = \"1\" # first\nalpha = 2\n\n[db]\n# login\nuser = \"x\"\n"
+        );
+    }
+}
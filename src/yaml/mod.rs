@@ -1,33 +1,1267 @@
-use failure::Error;
-use serde_yaml::{Mapping, Value};
+use failure::{Error, ResultExt};
+use std::ops::Range;
+use yaml_rust::parser::{Event, Parser};
+use yaml_rust::scanner::{Marker, TScalarStyle, TokenType};
+use yaml_rust::Yaml;
 
-pub fn traverse_yml<'a, F>(value: &'a Mapping, f: &F) -> Result<Mapping, Error>
+/// Tag which marks values that should be encrypted.
+pub const ENCRYPT_TAG: &str = "!encrypt";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// A scalar of a YAML stream together with the location of its source
+/// text, so it can be replaced without touching the rest of the file.
+#[derive(Clone, Debug)]
+pub struct Scalar {
+    pub document: usize,
+    pub path: Vec<Segment>,
+    pub value: String,
+    pub tag: Option<String>,
+    pub is_key: bool,
+    span: Range<usize>,
+    tag_span: Option<Range<usize>>,
+    style: TScalarStyle,
+    flow: bool,
+    block_indent: usize,
+}
+
+impl Scalar {
+    /// Whether the scalar has no source text, e.g. the value of `key:`.
+    pub fn is_empty(&self) -> bool {
+        self.span.start == self.span.end
+    }
+
+    /// Whether the value is tagged to be encrypted.
+    pub fn is_marked(&self) -> bool {
+        !self.is_key && self.tag.as_deref() == Some(ENCRYPT_TAG)
+    }
+}
+
+/// New content of a scalar, either a value which is formatted to fit its
+/// place or source text which is copied as it is.
+#[derive(Clone, Copy)]
+enum Content<'a> {
+    Value(&'a str),
+    Source(&'a str),
+}
+
+/// A mapping or sequence, kept to find where new entries can be inserted.
+struct Collection {
+    document: usize,
+    path: Vec<Segment>,
+    is_key: bool,
+    is_mapping: bool,
+    flow: bool,
+    column: usize,
+    end: usize,
+}
+
+/// A parsed YAML stream which keeps its original source text.
+///
+/// Rewriting only touches the source text of replaced scalars, so comments,
+/// blank lines, quoting, anchors and formatting of everything else survive.
+pub struct YamlFile {
+    source: String,
+    scalars: Vec<Scalar>,
+    collections: Vec<Collection>,
+}
+
+enum Position {
+    Mapping {
+        key: Option<String>,
+        key_end: usize,
+        expects_key: bool,
+    },
+    Sequence {
+        index: usize,
+    },
+}
+
+/// A collection which is currently being parsed.
+struct Frame {
+    position: Position,
+    in_key: bool,
+    flow: bool,
+    column: Option<usize>,
+    end: usize,
+    collection: usize,
+}
+
+impl Frame {
+    fn segment(&self) -> Segment {
+        match &self.position {
+            Position::Mapping {
+                key: Some(key),
+                expects_key: false,
+                ..
+            } => Segment::Key(key.to_owned()),
+            Position::Mapping { .. } => Segment::Key("?".to_owned()),
+            Position::Sequence { index } => Segment::Index(*index),
+        }
+    }
+
+    fn expects_key(&self) -> bool {
+        match self.position {
+            Position::Mapping { expects_key, .. } => expects_key,
+            Position::Sequence { .. } => false,
+        }
+    }
+
+    /// Block mappings are reported at their first `:`, so their column is
+    /// taken from the first key instead.
+    fn observe(&mut self, mark: &Marker) {
+        if self.column.is_none() {
+            self.column = Some(mark.col());
+        }
+    }
+
+    /// Moves on to the next key, value or item once a child node is complete.
+    fn advance(&mut self, scalar_key: Option<String>, end: usize) {
+        self.end = self.end.max(end);
+        match &mut self.position {
+            Position::Mapping {
+                key,
+                key_end,
+                expects_key,
+            } => {
+                if *expects_key {
+                    *key = scalar_key;
+                    *key_end = end;
+                }
+                *expects_key = !*expects_key;
+            }
+            Position::Sequence { index } => *index += 1,
+        }
+    }
+}
+
+impl YamlFile {
+    pub fn parse(source: String) -> Result<YamlFile, Error> {
+        // yaml-rust reports positions as char indices.
+        let mut offsets: Vec<usize> = source.char_indices().map(|(offset, _)| offset).collect();
+        offsets.push(source.len());
+        let offset = |mark: &Marker| offsets[mark.index()];
+
+        let mut scalars = vec![];
+        let mut collections = vec![];
+        let mut stack: Vec<Frame> = vec![];
+        let mut document = 0;
+        let mut parser = Parser::new(source.chars());
+
+        loop {
+            let (event, mark) = parser.next().context("Could not parse result to YAML.")?;
+
+            let path: Vec<Segment> = stack.iter().map(Frame::segment).collect();
+            let expects_key = stack.last().map(Frame::expects_key).unwrap_or(false);
+            let is_key = expects_key || stack.last().map(|frame| frame.in_key).unwrap_or(false);
+            let flow = stack.iter().any(|frame| frame.flow);
+
+            match event {
+                Event::StreamEnd => break,
+                Event::DocumentEnd => document += 1,
+                Event::Scalar(value, style, _, tag) => {
+                    let tag = tag.map(|tag| match tag {
+                        TokenType::Tag(handle, suffix) => format!("{}{}", handle, suffix),
+                        _ => unreachable!(),
+                    });
+
+                    let mut start = match style {
+                        TScalarStyle::Literal | TScalarStyle::Foled => {
+                            block_scalar_start(&source, offset(&mark))
+                        }
+                        _ => offset(&mark),
+                    };
+                    let is_empty = style == TScalarStyle::Plain
+                        && (value.is_empty() || value == "~" && !source[start..].starts_with('~'));
+                    let end = if is_empty {
+                        // Empty values are reported at the next token, move
+                        // them right behind the `:` of their key instead.
+                        if let Some(Position::Mapping {
+                            key_end,
+                            expects_key: false,
+                            ..
+                        }) = stack.last().map(|frame| &frame.position)
+                        {
+                            if let Some(colon) = source[*key_end..start].find(':') {
+                                start = key_end + colon + 1;
+                            }
+                        }
+                        start
+                    } else {
+                        scalar_end(&source, start, style, &value)
+                    };
+                    let tag_span = match tag {
+                        Some(_) if is_empty => {
+                            // Without a value only the tag itself is dropped,
+                            // the value is inserted right behind it.
+                            let tag_start = start + indentation(&source[start..]);
+                            let tag_span = if source[tag_start..].starts_with('!') {
+                                Some(tag_start..tag_start)
+                            } else {
+                                tag_span(&source, start)
+                            };
+                            tag_span.map(|tag_span| {
+                                start = tag_span.start
+                                    + source[tag_span.start..]
+                                        .find(char::is_whitespace)
+                                        .unwrap_or_else(|| source.len() - tag_span.start);
+                                tag_span.start..start
+                            })
+                        }
+                        Some(_) => tag_span(&source, start),
+                        None => None,
+                    };
+                    let end = end.max(start);
+
+                    let mut path = path;
+                    if expects_key {
+                        path.pop();
+                        path.push(Segment::Key(value.clone()));
+                    }
+
+                    scalars.push(Scalar {
+                        document,
+                        path,
+                        value: value.clone(),
+                        tag,
+                        is_key,
+                        span: start..end,
+                        tag_span,
+                        style,
+                        flow,
+                        block_indent: stack
+                            .last()
+                            .map(|frame| frame.column.unwrap_or(0) + 2)
+                            .unwrap_or(2),
+                    });
+
+                    if let Some(frame) = stack.last_mut() {
+                        frame.observe(&mark);
+                        frame.advance(Some(value), if is_empty { 0 } else { end });
+                    }
+                }
+                Event::Alias(_) => {
+                    if let Some(frame) = stack.last_mut() {
+                        frame.observe(&mark);
+                        frame.advance(None, offset(&mark));
+                    }
+                }
+                Event::MappingStart(_) | Event::SequenceStart(_) => {
+                    let start = offset(&mark);
+                    let is_mapping = matches!(event, Event::MappingStart(_));
+
+                    if let Some(frame) = stack.last_mut() {
+                        frame.observe(&mark);
+                    }
+                    stack.push(Frame {
+                        position: if is_mapping {
+                            Position::Mapping {
+                                key: None,
+                                key_end: start,
+                                expects_key: true,
+                            }
+                        } else {
+                            Position::Sequence { index: 0 }
+                        },
+                        in_key: is_key,
+                        flow: source[start..].starts_with(&['{', '['][..]),
+                        column: if is_mapping { None } else { Some(mark.col()) },
+                        end: start,
+                        collection: collections.len(),
+                    });
+                    collections.push(Collection {
+                        document,
+                        path,
+                        is_key,
+                        is_mapping,
+                        flow: false,
+                        column: 0,
+                        end: start,
+                    });
+                }
+                Event::MappingEnd | Event::SequenceEnd => {
+                    let frame = stack.pop().expect("collection ends after it starts");
+                    let collection = &mut collections[frame.collection];
+                    collection.flow = frame.flow;
+                    collection.column = frame.column.unwrap_or(0);
+                    collection.end = frame.end;
+
+                    if let Some(parent) = stack.last_mut() {
+                        parent.advance(None, frame.end);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(YamlFile {
+            source,
+            scalars,
+            collections,
+        })
+    }
+
+    pub fn scalars(&self) -> &[Scalar] {
+        &self.scalars
+    }
+
+    /// Returns the line number of a scalar, starting at 1.
+    pub fn line(&self, scalar: &Scalar) -> usize {
+        self.source[..scalar.span.start].matches('\n').count() + 1
+    }
+
+    /// Returns the source text of a single line scalar together with its
+    /// tag, so it can be copied elsewhere without changing its type.
+    pub fn scalar_source(&self, scalar: &Scalar) -> Option<String> {
+        let text = &self.source[scalar.span.clone()];
+        let is_block = matches!(scalar.style, TScalarStyle::Literal | TScalarStyle::Foled);
+        if scalar.is_empty() || is_block || text.contains('\n') {
+            return None;
+        }
+        match &scalar.tag_span {
+            Some(tag_span) => Some(format!(
+                "{} {}",
+                self.source[tag_span.clone()].trim_end(),
+                text
+            )),
+            None => Some(text.to_owned()),
+        }
+    }
+
+    /// Whether the first document has a value at `path`.
+    pub fn contains(&self, path: &[Segment]) -> bool {
+        self.scalars
+            .iter()
+            .any(|scalar| scalar.document == 0 && !scalar.is_key && scalar.path == path)
+            || self.collections.iter().any(|collection| {
+                collection.document == 0 && !collection.is_key && collection.path == path
+            })
+    }
+
+    /// Returns the source text with the scalars at the given indices
+    /// replaced by the given values.
+    pub fn rewrite(&self, replacements: &[(usize, String)]) -> String {
+        self.splice(
+            replacements
+                .iter()
+                .filter_map(|(index, value)| self.replace(&self.scalars[*index], value, "", false))
+                .collect(),
+        )
+    }
+
+    /// Like `rewrite`, but copies the given source text of scalars as it is,
+    /// replacing their tags.
+    pub fn rewrite_source(&self, replacements: &[(usize, String)]) -> String {
+        self.splice(
+            replacements
+                .iter()
+                .flat_map(|(index, text)| {
+                    let scalar = &self.scalars[*index];
+                    self.untag(scalar).into_iter().chain(self.replace_text(
+                        scalar,
+                        text.clone(),
+                        true,
+                    ))
+                })
+                .collect(),
+        )
+    }
+
+    /// Like `rewrite`, but additionally tags the new values with `!encrypt`.
+    pub fn mark(&self, replacements: &[(usize, String)]) -> String {
+        let prefix = format!("{} ", ENCRYPT_TAG);
+        self.splice(
+            replacements
+                .iter()
+                .flat_map(|(index, value)| {
+                    let scalar = &self.scalars[*index];
+                    self.untag(scalar)
+                        .into_iter()
+                        .chain(self.replace(scalar, value, &prefix, true))
+                })
+                .collect(),
+        )
+    }
+
+    /// Like `rewrite`, but additionally drops the tags of the new values.
+    /// The scalars in `sources` get the given source text copied as it is
+    /// instead, like in `rewrite_source`.
+    pub fn unmark(&self, replacements: &[(usize, String)], sources: &[(usize, String)]) -> String {
+        let values = replacements.iter().flat_map(|(index, value)| {
+            let scalar = &self.scalars[*index];
+            self.untag(scalar)
+                .into_iter()
+                .chain(self.replace(scalar, value, "", true))
+        });
+        let sources = sources.iter().flat_map(|(index, text)| {
+            let scalar = &self.scalars[*index];
+            self.untag(scalar)
+                .into_iter()
+                .chain(self.replace_text(scalar, text.clone(), true))
+        });
+        self.splice(values.chain(sources).collect())
+    }
+
+    fn replace(
+        &self,
+        scalar: &Scalar,
+        value: &str,
+        prefix: &str,
+        untagged: bool,
+    ) -> Option<(Range<usize>, String)> {
+        let text = format!("{}{}", prefix, format_scalar(scalar, value));
+
+        // A comment after a new block scalar would become part of its
+        // content, so it is moved behind the block header instead.
+        let line_end = self.source[scalar.span.end..]
+            .find('\n')
+            .map(|i| scalar.span.end + i)
+            .unwrap_or_else(|| self.source.len());
+        let comment = &self.source[scalar.span.end..line_end];
+        match text.find('\n') {
+            Some(header_end) if !comment.trim().is_empty() => {
+                let (header, content) = text.split_at(header_end);
+                let (range, text) = self.replace_text(scalar, header.to_owned(), untagged)?;
+                Some((
+                    range.start..line_end,
+                    format!("{}{}{}", text, comment, content),
+                ))
+            }
+            _ => self.replace_text(scalar, text, untagged),
+        }
+    }
+
+    fn replace_text(
+        &self,
+        scalar: &Scalar,
+        text: String,
+        untagged: bool,
+    ) -> Option<(Range<usize>, String)> {
+        // The comment of a replaced block header stays behind the new text.
+        let text = match self.header_comment(scalar) {
+            Some(comment) => match text.find('\n') {
+                Some(header_end) => {
+                    format!("{}{}{}", &text[..header_end], comment, &text[header_end..])
+                }
+                None => format!("{}{}", text, comment),
+            },
+            None => text,
+        };
+        let separator = if scalar.is_empty() && (scalar.tag_span.is_none() || !untagged) {
+            " "
+        } else {
+            ""
+        };
+        Some((scalar.span.clone(), format!("{}{}", separator, text)))
+    }
+
+    /// Returns the comment after the header of a block scalar, including
+    /// the whitespace before it.
+    fn header_comment(&self, scalar: &Scalar) -> Option<&str> {
+        if !matches!(scalar.style, TScalarStyle::Literal | TScalarStyle::Foled) {
+            return None;
+        }
+        let text = &self.source[scalar.span.clone()];
+        let header = text.split('\n').next().unwrap_or_default().trim_end();
+        let comment_start = header.find('#')?;
+        Some(&header[header[..comment_start].trim_end().len()..])
+    }
+
+    fn untag(&self, scalar: &Scalar) -> Option<(Range<usize>, String)> {
+        scalar
+            .tag_span
+            .clone()
+            .map(|tag_span| (tag_span, String::new()))
+    }
+
+    /// Returns the source text with the given byte ranges replaced.
+    fn splice(&self, mut edits: Vec<(Range<usize>, String)>) -> String {
+        edits.sort_by_key(|(range, _)| range.start);
+
+        let mut output = String::with_capacity(self.source.len());
+        let mut position = 0;
+        for (range, text) in edits {
+            output.push_str(&self.source[position..range.start]);
+            output.push_str(&text);
+            position = range.end;
+        }
+        output.push_str(&self.source[position..]);
+        output
+    }
+
+    /// Returns the scalar at `path` of the first document.
+    pub fn get(&self, path: &[Segment]) -> Result<&Scalar, Error> {
+        let scalar = self
+            .scalars
+            .iter()
+            .find(|scalar| scalar.document == 0 && !scalar.is_key && scalar.path == path);
+
+        match scalar {
+            Some(scalar) => Ok(scalar),
+            None if self.collections.iter().any(|collection| {
+                collection.document == 0 && !collection.is_key && collection.path == path
+            }) =>
+            {
+                Err(format_err!("{} is not a scalar", display_path(path)))
+            }
+            None => Err(format_err!("No value at {}", display_path(path))),
+        }
+    }
+
+    /// Sets the value at `path` of the first document, creating missing
+    /// mappings on the way.
+    pub fn set(&self, path: &[Segment], value: &str) -> Result<String, Error> {
+        self.insert(path, Content::Value(value))
+    }
+
+    /// Like `set`, but copies the given source text of a scalar as it is.
+    pub fn set_source(&self, path: &[Segment], text: &str) -> Result<String, Error> {
+        self.insert(path, Content::Source(text))
+    }
+
+    fn insert(&self, path: &[Segment], content: Content) -> Result<String, Error> {
+        let find_scalar = |path: &[Segment]| {
+            self.scalars
+                .iter()
+                .position(|scalar| scalar.document == 0 && !scalar.is_key && scalar.path == path)
+        };
+        let find_collection = |path: &[Segment]| {
+            self.collections.iter().find(|collection| {
+                collection.document == 0 && !collection.is_key && collection.path == path
+            })
+        };
+
+        if let Some(index) = find_scalar(path) {
+            return Ok(match content {
+                Content::Value(value) => self.rewrite(&[(index, value.to_owned())]),
+                Content::Source(text) => self.rewrite_source(&[(index, text.to_owned())]),
+            });
+        }
+        if find_collection(path).is_some() {
+            return Err(format_err!("{} is not a scalar", display_path(path)));
+        }
+
+        for depth in (0..path.len()).rev() {
+            let (parent, missing) = path.split_at(depth);
+
+            if let Some(collection) = find_collection(parent) {
+                if !collection.is_mapping || collection.flow {
+                    return Err(format_err!(
+                        "Cannot add {} to {}, it is not a block mapping",
+                        display_path(path),
+                        display_path(parent)
+                    ));
+                }
+
+                let entries = format_entries(missing, content, collection.column)?;
+                let position = self.source[collection.end..]
+                    .find('\n')
+                    .map(|i| collection.end + i)
+                    .unwrap_or_else(|| self.source.len());
+                return Ok(format!(
+                    "{}\n{}{}",
+                    &self.source[..position],
+                    entries,
+                    &self.source[position..]
+                ));
+            }
+
+            if let Some(index) = find_scalar(parent) {
+                let scalar = &self.scalars[index];
+                if !scalar.is_empty() {
+                    return Err(format_err!("{} is not a mapping", display_path(parent)));
+                }
+                if !parent.is_empty() {
+                    // The entries go below the key, after a comment on its line.
+                    let entries = format_entries(missing, content, scalar.block_indent)?;
+                    let line_end = self.source[scalar.span.end..]
+                        .find('\n')
+                        .map(|i| scalar.span.end + i)
+                        .unwrap_or_else(|| self.source.len());
+                    return Ok(self.splice(vec![(line_end..line_end, format!("\n{}", entries))]));
+                }
+            }
+        }
+
+        // The document is empty, so the whole path is appended.
+        let entries = format_entries(path, content, 0)?;
+        match self.source.trim_end() {
+            "" => Ok(format!("{}\n", entries)),
+            source => Ok(format!("{}\n{}\n", source, entries)),
+        }
+    }
+
+    /// Removes the block mapping entry at `path` of the first document.
+    pub fn remove(&self, path: &[Segment]) -> Result<String, Error> {
+        let key = self
+            .scalars
+            .iter()
+            .find(|scalar| scalar.document == 0 && scalar.is_key && scalar.path == path)
+            .ok_or_else(|| format_err!("No value at {}", display_path(path)))?;
+
+        let line_start = self.source[..key.span.start]
+            .rfind('\n')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        if key.flow || !self.source[line_start..key.span.start].trim().is_empty() {
+            return Err(format_err!(
+                "Cannot remove {}, it is not on a line of its own",
+                display_path(path)
+            ));
+        }
+
+        let value_end = self
+            .scalars
+            .iter()
+            .filter(|scalar| scalar.document == 0 && !scalar.is_key && scalar.path == path)
+            .map(|scalar| scalar.span.end)
+            .chain(
+                self.collections
+                    .iter()
+                    .filter(|collection| {
+                        collection.document == 0 && !collection.is_key && collection.path == path
+                    })
+                    .map(|collection| collection.end),
+            )
+            .fold(key.span.end, usize::max);
+        let line_end = self.source[value_end..]
+            .find('\n')
+            .map(|i| value_end + i + 1)
+            .unwrap_or_else(|| self.source.len());
+
+        Ok(self.splice(vec![(line_start..line_end, String::new())]))
+    }
+}
+
+/// Parses a path like `db.replicas[0].password`.
+pub fn parse_path(path: &str) -> Result<Vec<Segment>, Error> {
+    let invalid = || format_err!("Invalid path {}", path);
+
+    let mut segments = vec![];
+    for part in path.split('.') {
+        let (key, mut indices) = match part.find('[') {
+            Some(i) => part.split_at(i),
+            None => (part, ""),
+        };
+        if !key.is_empty() {
+            segments.push(Segment::Key(key.to_owned()));
+        } else if indices.is_empty() {
+            return Err(invalid());
+        }
+
+        while !indices.is_empty() {
+            let close = indices.find(']').ok_or_else(invalid)?;
+            if !indices.starts_with('[') {
+                return Err(invalid());
+            }
+            let index = indices[1..close].parse().map_err(|_| invalid())?;
+            segments.push(Segment::Index(index));
+            indices = &indices[close + 1..];
+        }
+    }
+    Ok(segments)
+}
+
+/// Formats nested block mapping entries leading to `value`.
+fn format_entries(path: &[Segment], content: Content, indent: usize) -> Result<String, Error> {
+    let mut lines = vec![];
+    for (depth, segment) in path.iter().enumerate() {
+        let key = match segment {
+            Segment::Key(key) if is_plain_safe(key, false) => key.to_owned(),
+            Segment::Key(key) => format_double_quoted(key),
+            Segment::Index(_) => {
+                return Err(format_err!(
+                    "Cannot create sequence item at {}",
+                    display_path(path)
+                ))
+            }
+        };
+        let column = indent + 2 * depth;
+        let padding = " ".repeat(column);
+
+        if depth + 1 == path.len() {
+            let formatted = match content {
+                Content::Source(text) => text.to_owned(),
+                Content::Value(value) if is_plain_safe(value, false) => value.to_owned(),
+                Content::Value(value) if value.contains('\n') && is_literal_safe(value) => {
+                    format_literal(value, column + 2)
+                }
+                Content::Value(value) => format_double_quoted(value),
+            };
+            lines.push(format!("{}{}: {}", padding, key, formatted));
+        } else {
+            lines.push(format!("{}{}:", padding, key));
+        }
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Formats a value on a single line, quoting it only if needed.
+pub fn format_inline(value: &str) -> String {
+    if is_plain_safe(value, false) {
+        value.to_owned()
+    } else {
+        format_double_quoted(value)
+    }
+}
+
+pub fn traverse_yml<F>(source: &str, f: &F) -> Result<String, Error>
 where
     F: Fn(&mut String) -> Result<Option<String>, Error>,
 {
-    let mut new_yml: Mapping = Mapping::new();
+    let yml = YamlFile::parse(source.to_owned())?;
+
+    let mut replacements = vec![];
+    for (index, scalar) in yml.scalars().iter().enumerate() {
+        if scalar.is_empty() {
+            continue;
+        }
+
+        let new_value = f(&mut scalar.value.to_owned()).with_context(|_| {
+            format!("Failed to replace value at {}", display_path(&scalar.path))
+        })?;
 
-    for entry in value.into_iter() {
-        match entry {
-            (Value::String(key), Value::String(s)) => {
-                let new_value = f(&mut s.to_owned())?.unwrap_or_else(|| s.to_owned());
-                new_yml.insert(Value::String(key.to_owned()), Value::String(new_value));
+        match new_value {
+            Some(_) if scalar.is_key => {
+                return Err(format_err!(
+                    "Cannot replace mapping key at {}",
+                    display_path(&scalar.path)
+                ))
             }
-            (Value::String(key), Value::Mapping(map)) => {
-                let new_mapping = traverse_yml(&map, f)?;
-                new_yml.insert(Value::String(key.to_owned()), Value::Mapping(new_mapping));
+            Some(new_value) => replacements.push((index, new_value)),
+            None => (),
+        }
+    }
+    Ok(yml.rewrite(&replacements))
+}
+
+pub fn display_path(path: &[Segment]) -> String {
+    if path.is_empty() {
+        return "document root".to_owned();
+    }
+
+    let mut display = String::new();
+    for segment in path {
+        match segment {
+            Segment::Key(key) if display.is_empty() => display.push_str(key),
+            Segment::Key(key) => {
+                display.push('.');
+                display.push_str(key);
             }
-            (Value::String(key), Value::Sequence(seq)) => {
-                new_yml.insert(Value::String(key.to_owned()), Value::Sequence(seq.clone()));
+            Segment::Index(index) => display.push_str(&format!("[{}]", index)),
+        }
+    }
+    display
+}
+
+/// Finds the end of the source text of a scalar starting at `start`.
+fn scalar_end(source: &str, start: usize, style: TScalarStyle, value: &str) -> usize {
+    let bytes = source.as_bytes();
+    match style {
+        TScalarStyle::SingleQuoted => {
+            let mut i = start + 1;
+            while i < bytes.len() {
+                match bytes[i] {
+                    b'\'' if bytes.get(i + 1) == Some(&b'\'') => i += 2,
+                    b'\'' => return i + 1,
+                    _ => i += 1,
+                }
             }
-            (Value::String(key), Value::Number(num)) => {
-                new_yml.insert(Value::String(key.to_owned()), Value::Number(num.clone()));
+            bytes.len()
+        }
+        TScalarStyle::DoubleQuoted => {
+            let mut i = start + 1;
+            while i < bytes.len() {
+                match bytes[i] {
+                    b'\\' => i += 2,
+                    b'"' => return i + 1,
+                    _ => i += 1,
+                }
             }
-            (Value::String(key), Value::Bool(boolean)) => {
-                new_yml.insert(Value::String(key.to_owned()), Value::Bool(*boolean));
+            bytes.len()
+        }
+        TScalarStyle::Literal | TScalarStyle::Foled => block_scalar_end(source, start),
+        _ => {
+            // Plain scalars only differ from their value by line folding, so
+            // matching every non-whitespace char of the value finds the end.
+            let mut expected = value.chars().filter(|c| !c.is_whitespace());
+            let mut next = expected.next();
+            let mut end = start;
+            for (i, c) in source[start..].char_indices() {
+                match next {
+                    Some(e) if e == c => {
+                        end = start + i + c.len_utf8();
+                        next = expected.next();
+                    }
+                    Some(_) if c.is_whitespace() => (),
+                    _ => break,
+                }
             }
-            _ => (),
+            end
+        }
+    }
+}
+
+/// Finds the tag in front of the scalar starting at `start`, possibly
+/// separated by an anchor, including the whitespace following it.
+fn tag_span(source: &str, start: usize) -> Option<Range<usize>> {
+    let mut position = start;
+    for _ in 0..2 {
+        let before = source[..position].trim_end();
+        let token_start = before
+            .rfind(|c: char| c.is_ascii_whitespace() || "[{,".contains(c))
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let token = &before[token_start..];
+
+        if token.starts_with('!') {
+            let token_end = token_start + token.len();
+            let next =
+                token_end + (source[token_end..].len() - source[token_end..].trim_start().len());
+            return Some(token_start..next);
+        }
+        if !token.starts_with('&') {
+            break;
+        }
+        position = token_start;
+    }
+    None
+}
+
+/// Finds the `|` or `>` indicator of a block scalar whose content starts at
+/// `content_start`.
+fn block_scalar_start(source: &str, content_start: usize) -> usize {
+    let mut line_start = source[..content_start]
+        .rfind('\n')
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let header = loop {
+        let header_start = source[..line_start.saturating_sub(1)]
+            .rfind('\n')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let header = &source[header_start..line_start];
+        if !header.trim().is_empty() || header_start == 0 {
+            break header_start..line_start;
+        }
+        line_start = header_start;
+    };
+
+    let line = &source[header.clone()];
+    let line = match line.find(" #") {
+        Some(comment) => &line[..comment],
+        None => line,
+    };
+    line.trim_end()
+        .rfind(&['|', '>'][..])
+        .map(|i| header.start + i)
+        .unwrap_or(content_start)
+}
+
+fn block_scalar_end(source: &str, start: usize) -> usize {
+    let line_start = source[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let parent_indent = indentation(&source[line_start..]);
+
+    let header_end = source[start..]
+        .find('\n')
+        .map(|i| start + i)
+        .unwrap_or_else(|| source.len());
+    let mut end = start
+        + source[start..header_end]
+            .find(char::is_whitespace)
+            .unwrap_or(header_end - start);
+
+    let mut block_indent = None;
+    let mut position = header_end;
+    while position < source.len() {
+        let line_start = position + 1;
+        let line_end = source[line_start..]
+            .find('\n')
+            .map(|i| line_start + i)
+            .unwrap_or_else(|| source.len());
+        let line = source[line_start..line_end].trim_end_matches('\r');
+        position = line_end;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let indent = indentation(line);
+        match block_indent {
+            None if indent > parent_indent => block_indent = Some(indent),
+            Some(block_indent) if indent >= block_indent => (),
+            _ => break,
+        }
+        end = line_start + line.len();
+    }
+    end
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/// Formats a value to replace the given scalar, keeping its style where
+/// the value allows it.
+fn format_scalar(scalar: &Scalar, value: &str) -> String {
+    let printable = !value
+        .chars()
+        .any(|c| c.is_control() && c != '\n' && c != '\t');
+    let multiline = value.trim_end_matches('\n').contains('\n');
+
+    match scalar.style {
+        TScalarStyle::Literal | TScalarStyle::Foled
+            if printable && !scalar.flow && is_literal_safe(value) =>
+        {
+            format_literal(value, scalar.block_indent)
+        }
+        _ if multiline && printable && !scalar.flow && is_literal_safe(value) => {
+            format_literal(value, scalar.block_indent)
+        }
+        TScalarStyle::Plain if is_plain_safe(value, scalar.flow) => value.to_owned(),
+        TScalarStyle::SingleQuoted if printable && !value.contains('\n') => {
+            format!("'{}'", value.replace('\'', "''"))
+        }
+        _ => format_double_quoted(value),
+    }
+}
+
+fn is_plain_safe(value: &str, flow: bool) -> bool {
+    let mut chars = value.chars();
+    let (first, second) = match chars.next() {
+        Some(first) => (first, chars.next().unwrap_or(' ')),
+        None => return false,
+    };
+    // `-`, `?` and `:` only act as indicators when followed by a space.
+    let indicator = "-?:,[]{}#&*!|>'\"%@`".contains(first)
+        && (second.is_whitespace() || !"-?:".contains(first));
+    let resolves_to_string =
+        matches!(Yaml::from_str(value), Yaml::String(_)) && !is_yaml_1_1_non_string(value);
+
+    !indicator
+        && resolves_to_string
+        && value.trim() == value
+        && !value.contains(": ")
+        && !value.contains(" #")
+        && !value.ends_with(':')
+        && !value.chars().any(char::is_control)
+        && !(flow && value.contains(|c| ",[]{}".contains(c)))
+}
+
+/// Whether YAML 1.1 consumers, e.g. Kubernetes and Helm, resolve a plain
+/// scalar to a boolean, number or merge key although YAML 1.2 does not.
+fn is_yaml_1_1_non_string(value: &str) -> bool {
+    const BOOLEANS: &[&str] = &[
+        "y", "Y", "yes", "Yes", "YES", "n", "N", "no", "No", "NO", "true", "True", "TRUE", "false",
+        "False", "FALSE", "on", "On", "ON", "off", "Off", "OFF",
+    ];
+    let is_digits = |part: &str| {
+        part.starts_with(|c: char| c.is_ascii_digit())
+            && part.chars().all(|c| c.is_ascii_digit() || c == '_')
+    };
+
+    if BOOLEANS.contains(&value) || value == "<<" || value == "=" {
+        return true;
+    }
+    if [".nan", ".NaN", ".NAN"].contains(&value) {
+        return true;
+    }
+    let number = value.trim_start_matches(['-', '+']);
+    if [".inf", ".Inf", ".INF"].contains(&number) {
+        return true;
+    }
+
+    // Binary, hexadecimal, octal and decimal integers.
+    if let Some(digits) = number.strip_prefix("0b") {
+        return !digits.is_empty() && digits.chars().all(|c| "01_".contains(c));
+    }
+    if let Some(digits) = number.strip_prefix("0x") {
+        return !digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit() || c == '_');
+    }
+    if is_digits(number) {
+        return true;
+    }
+
+    // Base 60 numbers like `1:20` or `1:20.5`.
+    let mut parts = number.split(':');
+    let first = parts.next().unwrap_or_default();
+    let rest: Vec<_> = parts.collect();
+    if !rest.is_empty() && is_digits(first) {
+        let (last, middle) = rest.split_last().unwrap();
+        let is_sexagesimal =
+            |part: &str| (1..=2).contains(&part.len()) && part.chars().all(|c| c.is_ascii_digit());
+        let last = last.split('.').next().unwrap_or_default();
+        return middle.iter().all(|part| is_sexagesimal(part)) && is_sexagesimal(last);
+    }
+
+    // Floats like `1_000.5`, `.5` or `1.e+3`.
+    let (mantissa, exponent) = match number.find(['e', 'E']) {
+        Some(e) => (&number[..e], Some(&number[e + 1..])),
+        None => (number, None),
+    };
+    let exponent_valid = match exponent {
+        Some(exponent) => {
+            exponent.starts_with(['-', '+'])
+                && exponent.len() > 1
+                && exponent[1..].chars().all(|c| c.is_ascii_digit())
+        }
+        None => true,
+    };
+    match mantissa.find('.') {
+        Some(dot) => {
+            let integer = &mantissa[..dot];
+            (integer.is_empty() || is_digits(integer))
+                && mantissa[dot + 1..]
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || c == '.')
+                && exponent_valid
+        }
+        None => false,
+    }
+}
+
+fn is_literal_safe(value: &str) -> bool {
+    !value.starts_with(' ')
+        && !value.starts_with('\n')
+        && !value.contains('\r')
+        && !value
+            .lines()
+            .any(|line| line.ends_with(' ') || line.ends_with('\t'))
+}
+
+fn format_literal(value: &str, indent: usize) -> String {
+    let content = value.trim_end_matches('\n');
+    let trailing_newlines = value.len() - content.len();
+    let chomping = match trailing_newlines {
+        0 => "-",
+        1 => "",
+        _ => "+",
+    };
+    let padding = " ".repeat(indent);
+
+    let mut literal = format!("|{}", chomping);
+    for line in content.split('\n') {
+        literal.push('\n');
+        if !line.is_empty() {
+            literal.push_str(&padding);
+            literal.push_str(line);
+        }
+    }
+    for _ in 1..trailing_newlines {
+        literal.push('\n');
+    }
+    literal
+}
+
+fn format_double_quoted(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::unwrap_vault;
+
+    #[test]
+    fn rewrites_only_vaults() {
+        let source = "# database settings\n\
+                      db:\n  \
+                        user: &user admin   # the admin\n\n  \
+                        password: VAULT:hunter2\n  \
+                        quoted: 'VAULT:it''s'\n  \
+                        owner: *user\n\
+                      replicas:\n  \
+                        - {host: a, password: \"VAULT:x\"}\n  \
+                        - [VAULT:y, [VAULT:z]]\n\
+                      ports:\n  \
+                        8080: VAULT:http\n\
+                      yaml11: [VAULT:yes, VAULT:no, VAULT:on, VAULT:off, VAULT:Yes, VAULT:ON, \
+                               VAULT:y, VAULT:n, VAULT:1_000, VAULT:1:20, VAULT:0x1f, VAULT:yep]\n\
+                      empty:\n";
+
+        let expected = "# database settings\n\
+                        db:\n  \
+                          user: &user admin   # the admin\n\n  \
+                          password: hunter2\n  \
+                          quoted: 'it''s'\n  \
+                          owner: *user\n\
+                        replicas:\n  \
+                          - {host: a, password: \"x\"}\n  \
+                          - [\"y\", [z]]\n\
+                        ports:\n  \
+                          8080: http\n\
+                        yaml11: [\"yes\", \"no\", \"on\", \"off\", \"Yes\", \"ON\", \
+                                 \"y\", \"n\", \"1_000\", \"1:20\", \"0x1f\", yep]\n\
+                        empty:\n";
+        assert_eq!(
+            traverse_yml(source, &|val: &mut String| unwrap_vault(val)).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn rewrites_every_document_of_a_stream() {
+        let source = "a: VAULT:1\n---\n- VAULT:2\n--- VAULT:three\n";
+        let expected = "a: \"1\"\n---\n- \"2\"\n--- three\n";
+        assert_eq!(
+            traverse_yml(source, &|val: &mut String| unwrap_vault(val)).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn formats_multiline_values_as_literal_blocks() {
+        let source = "certs:\n  - key: >-\n      VAULT:first\n      line\n\n    other: x\n";
+        let yml = YamlFile::parse(source.to_owned()).unwrap();
+        assert_eq!(yml.scalars()[2].value, "VAULT:first line");
+        let replaced = yml.rewrite(&[(2, "first\nsecond\n".to_owned())]);
+        assert_eq!(
+            replaced,
+            "certs:\n  - key: |\n      first\n      second\n\n    other: x\n"
+        );
+    }
+
+    fn set(source: &str, path: &str, value: &str) -> Result<String, Error> {
+        YamlFile::parse(source.to_owned())?.set(&parse_path(path)?, value)
+    }
+
+    #[test]
+    fn sets_values_at_paths() {
+        let source = "db:\n  user: admin # the admin\n  password: old\nempty:\n";
+
+        assert_eq!(
+            set(source, "db.password", "new").unwrap(),
+            "db:\n  user: admin # the admin\n  password: new\nempty:\n"
+        );
+        assert_eq!(
+            set(source, "db.credentials.password", "new").unwrap(),
+            "db:\n  user: admin # the admin\n  password: old\n  credentials:\n    password: new\nempty:\n"
+        );
+        assert_eq!(
+            set(source, "empty.password", "new").unwrap(),
+            "db:\n  user: admin # the admin\n  password: old\nempty:\n  password: new\n"
+        );
+        assert_eq!(
+            set("empty: # later\nb: 1", "empty.password", "new").unwrap(),
+            "empty: # later\n  password: new\nb: 1"
+        );
+        assert_eq!(
+            set("", "db.password", "true").unwrap(),
+            "db:\n  password: \"true\"\n"
+        );
+        assert!(set(source, "db.user.password", "new").is_err());
+        assert!(set(source, "db", "new").is_err());
+    }
+
+    #[test]
+    fn removes_entries() {
+        let source = "db:\n  user: admin\n  password: |\n    old\n    lines\n\
+                      nested:\n  a:\n    - 1\n  b: 2\nlast: x";
+        let remove = |path: &str| {
+            YamlFile::parse(source.to_owned())
+                .unwrap()
+                .remove(&parse_path(path).unwrap())
         };
+
+        assert_eq!(
+            remove("db.password").unwrap(),
+            "db:\n  user: admin\nnested:\n  a:\n    - 1\n  b: 2\nlast: x"
+        );
+        assert_eq!(
+            remove("nested.a").unwrap(),
+            "db:\n  user: admin\n  password: |\n    old\n    lines\nnested:\n  b: 2\nlast: x"
+        );
+        assert_eq!(
+            remove("last").unwrap(),
+            "db:\n  user: admin\n  password: |\n    old\n    lines\nnested:\n  a:\n    - 1\n  b: 2\n"
+        );
+        assert!(remove("db.missing").is_err());
+    }
+
+    #[test]
+    fn parses_paths() {
+        assert_eq!(
+            parse_path("db.replicas[0].password").unwrap(),
+            vec![
+                Segment::Key("db".to_owned()),
+                Segment::Key("replicas".to_owned()),
+                Segment::Index(0),
+                Segment::Key("password".to_owned()),
+            ]
+        );
+        assert!(parse_path("db..password").is_err());
+        assert!(parse_path("db[x]").is_err());
+    }
+
+    #[test]
+    fn marks_and_unmarks_values() {
+        let yml = YamlFile::parse("a: VAULT:x # old\nb: !!str VAULT:y\n".to_owned()).unwrap();
+        let replacements: Vec<(usize, String)> = yml
+            .scalars()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, scalar)| unwrap_vault(&scalar.value).unwrap().map(|v| (index, v)))
+            .collect();
+        assert_eq!(
+            yml.mark(&replacements),
+            "a: !encrypt x # old\nb: !encrypt \"y\"\n"
+        );
+
+        let yml = YamlFile::parse(
+            "a: !encrypt x\nb: !encrypt &b 'y'\nc: !encrypt\nd: [!encrypt z, w]\ne: *b\n"
+                .to_owned(),
+        )
+        .unwrap();
+        let replacements: Vec<(usize, String)> = yml
+            .scalars()
+            .iter()
+            .enumerate()
+            .filter(|(_, scalar)| scalar.is_marked())
+            .map(|(index, scalar)| (index, format!("VAULT:{}", scalar.value)))
+            .collect();
+        assert_eq!(
+            yml.unmark(&replacements, &[]),
+            "a: VAULT:x\nb: &b 'VAULT:y'\nc: \"VAULT:\"\nd: [VAULT:z, w]\ne: *b\n"
+        );
+    }
+
+    #[test]
+    fn restores_source_of_unchanged_vaults() {
+        let source = "cert: VAULT:a\\nb # pem\nport: !!str VAULT:80\n";
+        let yml = YamlFile::parse(source.to_owned()).unwrap();
+        let plain = |value: &str| unwrap_vault(value).unwrap().unwrap().replace("\\n", "\n");
+        let replacements: Vec<(usize, String)> = vec![
+            (1, plain(&yml.scalars()[1].value)),
+            (3, plain(&yml.scalars()[3].value)),
+        ];
+        let marked = yml.mark(&replacements);
+        assert_eq!(
+            marked,
+            "cert: !encrypt |- # pem\n  a\n  b\nport: !encrypt \"80\"\n"
+        );
+
+        let sources: Vec<(usize, String)> = vec![
+            (1, yml.scalar_source(&yml.scalars()[1]).unwrap()),
+            (3, yml.scalar_source(&yml.scalars()[3]).unwrap()),
+        ];
+        let marked = YamlFile::parse(marked).unwrap();
+        assert_eq!(marked.unmark(&[], &sources), source);
     }
-    Ok(new_yml)
 }